@@ -1,8 +1,14 @@
+use std::sync::Arc;
+
 use telegram_cjk_search_bot::*;
 
+use config::Config;
 use db::*;
 use handlers::*;
+use prune::Pruner;
+use queue::IndexQueue;
 use teloxide::{prelude::*, utils::command::BotCommands};
+use tokio::signal::unix::{signal, SignalKind};
 
 const DESCRIPTION: &str =
     "Search CJK(Chinese, Japanese, and Korean) messages in groups using inline mode.";
@@ -14,7 +20,46 @@ async fn main() {
     pretty_env_logger::init_timed();
 
     let bot = Bot::from_env();
-    Db::new().init().await;
+    let db = Db::open().await.unwrap_or_else(|e| {
+        log::error!("Failed to open the database: {e}");
+        std::process::exit(1);
+    });
+    let config = Arc::new(Config::from_env().unwrap_or_else(|e| {
+        log::error!("Invalid OPERATOR_ID: {e}");
+        std::process::exit(1);
+    }));
+    let queue = Arc::new(
+        IndexQueue::from_env(db.clone(), config.clone()).unwrap_or_else(|e| {
+            log::error!("Failed to open the index queue: {e}");
+            std::process::exit(1);
+        }),
+    );
+    let pruner = Arc::new(Pruner::from_env().unwrap_or_else(|e| {
+        log::error!("Invalid PRUNE_CHAT_ID: {e}");
+        std::process::exit(1);
+    }));
+    match link_preview::reload() {
+        Ok(l) => log::info!("Loaded link preview settings: {l}"),
+        Err(e) => {
+            log::error!("Failed to load link preview settings: {e}");
+            std::process::exit(1);
+        }
+    }
+    tokio::spawn(async {
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        while hangup.recv().await.is_some() {
+            match link_preview::reload() {
+                Ok(l) => log::info!("Reloaded link preview settings: {l}"),
+                Err(e) => log::error!(
+                    "Failed to reload link preview settings, kept the current ones: {e}"
+                ),
+            }
+        }
+    });
+    tokio::spawn({
+        let queue = queue.clone();
+        async move { queue.run().await }
+    });
 
     bot.set_my_commands(Command::bot_commands())
         .await
@@ -41,11 +86,14 @@ async fn main() {
     let handler = dptree::entry()
         .branch(Update::filter_message().endpoint(message_handler))
         .branch(Update::filter_edited_message().endpoint(message_handler))
+        .branch(Update::filter_channel_post().endpoint(message_handler))
+        .branch(Update::filter_edited_channel_post().endpoint(message_handler))
         .branch(Update::filter_inline_query().endpoint(inline_handler));
 
     log::info!("Started");
 
     Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![db, queue, pruner, config])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
@@ -3,10 +3,11 @@ use futures::future::join_all;
 use reqwest::Url;
 use serde::Deserialize;
 use serde_json::from_str;
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
 use telegram_cjk_search_bot::{
     db::{Db, Insertable},
     handlers::get_url_in_whitelist,
+    normalize::{normalize, variants},
     ogp::read_open_graph,
     types,
 };
@@ -41,6 +42,110 @@ struct Message {
     from_id: Option<String>,
     via_bot: Option<String>,
     text_entities: Vec<Entity>,
+    reply_to_message_id: Option<i32>,
+    forwarded_from: Option<String>,
+    forwarded_from_id: Option<String>,
+    photo: Option<String>,
+    file: Option<String>,
+    file_name: Option<String>,
+    media_type: Option<String>,
+    performer: Option<String>,
+    title: Option<String>,
+    poll: Option<Poll>,
+    contact_information: Option<Contact>,
+    place_name: Option<String>,
+    address: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Poll {
+    question: String,
+    answers: Vec<PollAnswer>,
+}
+
+#[derive(Deserialize, Clone)]
+struct PollAnswer {
+    text: String,
+}
+
+#[derive(Deserialize, Clone)]
+struct Contact {
+    first_name: String,
+    last_name: Option<String>,
+}
+
+impl Message {
+    /// The same kinds live messages get, as far as the export tells them apart.
+    fn kind(&self) -> types::MessageKind {
+        use types::MessageKind as Kind;
+        match self.media_type.as_deref() {
+            _ if self.photo.is_some() => Kind::Photo,
+            Some("video_file") => Kind::Video,
+            Some("animation") => Kind::Animation,
+            Some("audio_file") => Kind::Audio,
+            Some("voice_message") => Kind::Voice,
+            None if self.file.is_some() => Kind::Document,
+            _ if self.poll.is_some() => Kind::Poll,
+            _ if self.place_name.is_some() => Kind::Venue,
+            _ if self.contact_information.is_some() => Kind::Contact,
+            _ => Kind::Text,
+        }
+    }
+
+    /// The text and the media text, like `types::Message::text_of`.
+    fn text(&self) -> String {
+        Some(
+            self.text_entities
+                .iter()
+                .map(|e| e.text.clone())
+                .collect::<String>(),
+        )
+        .into_iter()
+        .chain(self.media_text())
+        .filter(|t| !t.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+
+    /// What the media of the message tells about itself, like `types::Message::text_of`.
+    fn media_text(&self) -> Vec<String> {
+        let file_name = self.file_name.clone().or_else(|| {
+            // Files left out of the export are noted in parentheses instead of a path.
+            self.file
+                .as_ref()
+                .filter(|f| !f.starts_with('('))
+                .and_then(|f| f.rsplit('/').next().map(ToString::to_string))
+        });
+        let media =
+            match self.kind() {
+                types::MessageKind::Document => file_name,
+                types::MessageKind::Audio => match (&self.performer, &self.title) {
+                    (Some(p), Some(t)) => Some(format!("{p} - {t}")),
+                    (p, t) => p.clone().or(t.clone()).or(file_name),
+                },
+                types::MessageKind::Poll => self.poll.as_ref().map(|p| {
+                    std::iter::once(p.question.as_str())
+                        .chain(p.answers.iter().map(|a| a.text.as_str()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }),
+                types::MessageKind::Venue => Some(
+                    [self.place_name.clone(), self.address.clone()]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ),
+                types::MessageKind::Contact => self.contact_information.as_ref().map(|c| match &c
+                    .last_name
+                {
+                    Some(l) if !l.is_empty() => format!("{} {l}", c.first_name),
+                    _ => c.first_name.clone(),
+                }),
+                _ => None,
+            };
+        media.into_iter().filter(|t| !t.trim().is_empty()).collect()
+    }
 }
 
 const INSERT_BATCH_LIMIT: usize = 2000;
@@ -48,7 +153,7 @@ const MAX_MARKED_CHANNEL_ID: i64 = -1000000000000;
 
 #[derive(Parser)]
 #[command(author, version, long_about = None)]
-#[command(about = "Import chat history from a json file to meilisearch db.")]
+#[command(about = "Import chat history from a json file to the search database.")]
 struct Cli {
     #[arg(default_value = "/app/history/result.json")]
     file: PathBuf,
@@ -57,7 +162,10 @@ struct Cli {
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init_timed();
-    Db::new().init().await;
+    let db = Db::open().await.unwrap_or_else(|e| {
+        log::error!("Failed to open the database: {e}");
+        std::process::exit(1);
+    });
     let cli = Cli::parse();
 
     let me = Bot::from_env().get_me().await.unwrap();
@@ -66,17 +174,17 @@ async fn main() {
     let content = read_content_from_file(&cli.file);
 
     assert!(
-        content.chat_type.contains("supergroup"),
-        "Chat type must be 'supergroup'"
+        content.chat_type.contains("supergroup") || content.chat_type.ends_with("channel"),
+        "Chat type must be 'supergroup' or a channel"
     );
     log::info!("Paresed {} items.", content.messages.len());
 
     let (messages_count, url_count, senders_count, message_handles, web_page_handles) =
-        process_messages(content, bot_username, bot_userid).await;
+        process_messages(&db, content, bot_username, bot_userid).await;
     log::info!("Found {messages_count} messages, {url_count} URLs, and {senders_count} senders.");
 
     log::info!("Crawling web pages.");
-    join_all(process_web_pages(web_page_handles).await).await;
+    join_all(process_web_pages(&db, web_page_handles).await).await;
 
     log::info!("Waiting for database to complete indexing.");
     join_all(message_handles).await;
@@ -92,6 +200,7 @@ fn read_content_from_file(file_path: &PathBuf) -> Content {
 
 #[allow(clippy::all)]
 async fn process_messages(
+    db: &Db,
     content: Content,
     bot_username: String,
     bot_userid: UserId,
@@ -107,6 +216,14 @@ async fn process_messages(
     let senders = Arc::new(Mutex::new(HashMap::<ChatId, String>::new()));
 
     let web_page_handles = Arc::new(Mutex::new(vec![]));
+    // Replies are exported with the id of the message they reply to only.
+    let texts = Arc::new(
+        content
+            .messages
+            .iter()
+            .map(|m| (m.id, m.text()))
+            .collect::<HashMap<_, _>>(),
+    );
     let mut handles = futures::future::join_all(
         content
             .messages
@@ -118,13 +235,23 @@ async fn process_messages(
                 let senders = senders.clone();
                 let web_pages_handles = web_page_handles.clone();
                 let bot_username = bot_username.clone();
+                let texts = texts.clone();
+                let db = db.clone();
                 tokio::spawn(async move {
                     let mut messages_batch = Vec::with_capacity(INSERT_BATCH_LIMIT);
                     let mut web_pages = vec![];
                     for message in c {
-                        if let Some(m) =
+                        if let Some(mut m) =
                             to_db_message(&bot_username, bot_userid, &message, &content.id).await
                         {
+                            m.reply_snippet = m
+                                .reply_to
+                                .and_then(|id| texts.get(&id))
+                                .filter(|t| !t.is_empty())
+                                .map(|t| types::snippet(t, types::REPLY_SNIPPET_LENGTH));
+                            if let (Some(id), Some(name)) = (m.forward_from_id, &m.forward_from) {
+                                senders.lock().await.insert(id, name.clone());
+                            }
                             let f = message
                                 .from
                                 .unwrap_or(format!("Deleted Account {}", m.sender.unwrap()));
@@ -158,7 +285,7 @@ async fn process_messages(
                     *messages_count.lock().await += messages_batch.len();
                     *url_count.lock().await += web_pages.len();
                     web_pages_handles.lock().await.extend(web_pages.into_iter());
-                    spawn_insert_task(messages_batch)
+                    spawn_insert_task(&db, messages_batch)
                 })
             }),
     )
@@ -174,12 +301,17 @@ async fn process_messages(
         .and_modify(|e| *e = content.name.clone())
         .or_insert(content.name);
     handles.push(spawn_insert_task(
+        db,
         senders
             .lock()
             .await
             .clone()
             .into_iter()
-            .map(|(id, name)| types::Sender { id, name })
+            .map(|(id, name)| types::Sender {
+                id,
+                name,
+                username: None,
+            })
             .collect::<Vec<_>>(),
     ));
 
@@ -210,33 +342,51 @@ async fn to_db_message(
         return None;
     }
 
-    let text = message
-        .text_entities
-        .iter()
-        .map(|e| e.text.clone())
-        .collect::<String>();
+    let text = message.text();
     if text.is_empty() {
         return None;
     }
+    let kind = message.kind();
 
     message.from_id.as_ref().map(|from_id| types::Message {
         key: format!("-100{}_{}", chat_id, message.id),
+        normalized: normalize(&text),
+        variants: variants(&text),
         text,
         from: None,
-        sender: Some(match from_id.starts_with("user") {
-            true => UserId(from_id[4..].parse::<u64>().unwrap()).into(),
-            false => ChatId(MAX_MARKED_CHANNEL_ID - from_id[7..].parse::<i64>().unwrap()),
-        }),
+        sender: Some(peer_id(from_id)),
         id: message.id,
+        thread_id: None,
+        topic: None,
+        reply_to: message.reply_to_message_id,
+        reply_snippet: None,
+        forward_from: message.forwarded_from.clone(),
+        forward_from_id: message.forwarded_from_id.as_deref().map(peer_id),
+        forward_link: None,
+        photo_file_id: None,
+        kind: (kind != types::MessageKind::Text).then_some(kind),
         via_bot: message.via_bot.clone(),
         chat_id: ChatId(format!("-100{}", chat_id).parse::<i64>().unwrap()),
         date: chrono::DateTime::from_timestamp(message.date_unixtime.parse().unwrap(), 0).unwrap(),
+        timestamp: message.date_unixtime.parse().unwrap(),
         web_page: None,
         thumbnail_url: None,
+        edit_date: None,
+        original: None,
+        revised: None,
     })
 }
 
+/// The id of a user, `user123`, or of a chat, `channel123`, as exports write them.
+fn peer_id(id: &str) -> ChatId {
+    match id.strip_prefix("user") {
+        Some(user) => UserId(user.parse::<u64>().unwrap()).into(),
+        None => ChatId(MAX_MARKED_CHANNEL_ID - id[7..].parse::<i64>().unwrap()),
+    }
+}
+
 async fn process_web_pages(
+    db: &Db,
     handles: Vec<JoinHandle<Option<types::Message>>>,
 ) -> Vec<JoinHandle<()>> {
     join_all(handles)
@@ -246,24 +396,19 @@ async fn process_web_pages(
         .collect::<Vec<_>>()
         .chunks(INSERT_BATCH_LIMIT)
         .map(|x| x.to_vec())
-        .map(spawn_insert_task)
+        .map(|x| spawn_insert_task(db, x))
         .collect::<Vec<_>>()
 }
 
-fn spawn_insert_task<T>(items: Vec<T>) -> JoinHandle<()>
+fn spawn_insert_task<T>(db: &Db, items: Vec<T>) -> JoinHandle<()>
 where
     T: Insertable + Sync + Send + 'static,
 {
+    let db = db.clone();
     tokio::spawn(async move {
-        if let Some(t) = Db::new().insert(&items).await {
-            t.wait_for_completion(
-                &Db::new().0,
-                Some(Duration::from_millis(200)),
-                Some(Duration::MAX),
-            )
-            .await
-            .unwrap();
-        };
+        if let Err(e) = db.insert_and_wait(&items).await {
+            log::error!("Failed to insert {} items: {e}", items.len());
+        }
     })
 }
 
@@ -517,4 +662,65 @@ mod import_test {
                 .is_none()
         );
     }
+
+    #[tokio::test]
+    async fn poll_message_test() {
+        let msg = serde_json::from_str::<super::Message>(
+            r#"
+            {
+                "id": 347,
+                "type": "message",
+                "date": "2024-01-10T17:21:31",
+                "date_unixtime": "1704878491",
+                "from": "Kris Hu",
+                "from_id": "user114514",
+                "poll": {
+                    "question": "午饭吃什么",
+                    "closed": false,
+                    "total_voters": 0,
+                    "answers": [
+                        {"text": "拉面", "voters": 0, "chosen": false},
+                        {"text": "寿司", "voters": 0, "chosen": false}
+                    ]
+                },
+                "text": "",
+                "text_entities": []
+            }
+        "#,
+        )
+        .unwrap();
+
+        let m = to_db_message("1", UserId(1), &msg, &ChatId(1145141919))
+            .await
+            .unwrap();
+        assert_eq!(m.text, "午饭吃什么\n拉面\n寿司");
+        assert_eq!(m.kind, Some(types::MessageKind::Poll));
+    }
+
+    #[tokio::test]
+    async fn document_message_test() {
+        let msg = serde_json::from_str::<super::Message>(
+            r#"
+            {
+                "id": 348,
+                "type": "message",
+                "date": "2024-01-10T17:22:31",
+                "date_unixtime": "1704878551",
+                "from": "Kris Hu",
+                "from_id": "user114514",
+                "file": "files/年度报告.pdf",
+                "mime_type": "application/pdf",
+                "text": "看看",
+                "text_entities": [{"type": "plain", "text": "看看"}]
+            }
+        "#,
+        )
+        .unwrap();
+
+        let m = to_db_message("1", UserId(1), &msg, &ChatId(1145141919))
+            .await
+            .unwrap();
+        assert_eq!(m.text, "看看\n年度报告.pdf");
+        assert_eq!(m.kind, Some(types::MessageKind::Document));
+    }
 }
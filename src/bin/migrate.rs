@@ -0,0 +1,53 @@
+use clap::Parser;
+use telegram_cjk_search_bot::db::*;
+
+#[derive(Parser)]
+#[command(author, version, long_about = None)]
+#[command(about = "Upgrade the stored documents and index settings to the current schema.")]
+struct Cli {
+    /// Only print the stored schema version
+    #[arg(long)]
+    check: bool,
+
+    /// Normalize stored messages again, after `NORMALIZE` has been changed
+    #[arg(long, conflicts_with = "check")]
+    normalize: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init_timed();
+    let cli = Cli::parse();
+
+    let db = Db::from_env().unwrap_or_else(|e| {
+        log::error!("Failed to open the database: {e}");
+        std::process::exit(1);
+    });
+    db.init().await.expect("failed to initialize the database");
+    let current = db
+        .schema_version()
+        .await
+        .expect("failed to read the schema version");
+    log::info!("Stored schema version is {current}, this build uses {SCHEMA_VERSION}.");
+    if cli.check {
+        return;
+    }
+
+    match db.migrate().await {
+        Ok(()) => log::info!("Schema is up to date."),
+        Err(e) => {
+            log::error!("Migration failed: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    if cli.normalize {
+        match db.normalize_messages().await {
+            Ok(()) => log::info!("Messages are normalized."),
+            Err(e) => {
+                log::error!("Normalization failed: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
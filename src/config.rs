@@ -0,0 +1,22 @@
+use teloxide::types::UserId;
+
+/// Settings read from the environment once at startup and shared with the handlers.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Keep earlier versions of edited messages, set by `KEEP_EDIT_HISTORY`.
+    pub keep_edit_history: bool,
+    /// The user running the bot, set by `OPERATOR_ID`, who may `/reload` its settings.
+    pub operator: Option<UserId>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, std::num::ParseIntError> {
+        Ok(Config {
+            keep_edit_history: std::env::var_os("KEEP_EDIT_HISTORY").is_some(),
+            operator: std::env::var("OPERATOR_ID")
+                .ok()
+                .map(|u| u.parse().map(UserId))
+                .transpose()?,
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Days, Months, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// The time zone set in `TZ`, used to show and parse dates. Defaults to UTC.
+pub fn local_tz() -> Tz {
+    Tz::from_str(&std::env::var("TZ").unwrap_or_default()).unwrap_or_default()
+}
+
+/// A span of time given on the command line, from `start` inclusive to `end` exclusive.
+///
+/// An absolute date covers the whole day, month or year it names, so `--until 2024-01` keeps
+/// messages from January. A relative one like `7d` is a single point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateRange {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl DateRange {
+    /// Parses `YYYY`, `YYYY-MM`, `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM` in `tz`, or a number
+    /// followed by `h`, `d`, `w`, `m` or `y` for that many hours, days, weeks, months or years
+    /// before `now`.
+    pub fn parse(s: &str, now: DateTime<Tz>) -> Result<Self, String> {
+        let tz = now.timezone();
+        if let Some(point) = parse_relative(s, now) {
+            let point = point.ok_or_else(|| format!("{s} is too far in the past"))?;
+            return Ok(DateRange {
+                start: point.to_utc(),
+                end: point.to_utc(),
+            });
+        }
+
+        let (start, end) = if let Ok(t) = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M") {
+            (t, t + TimeDelta::minutes(1))
+        } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            (d.into(), (d + Days::new(1)).into())
+        } else if let Ok(d) = NaiveDate::parse_from_str(&format!("{s}-01"), "%Y-%m-%d") {
+            (d.into(), (d + Months::new(1)).into())
+        } else if let Ok(d) = NaiveDate::parse_from_str(&format!("{s}-01-01"), "%Y-%m-%d") {
+            (d.into(), (d + Months::new(12)).into())
+        } else {
+            return Err(format!(
+                "invalid date {s}, expected e.g. 2024-01-31, 2024-01, 7d or 3m"
+            ));
+        };
+        let local = |t: NaiveDateTime| {
+            tz.from_local_datetime(&t)
+                .earliest()
+                .map(|t| t.to_utc())
+                .ok_or_else(|| format!("{s} does not exist in {tz}"))
+        };
+        Ok(DateRange {
+            start: local(start)?,
+            end: local(end)?,
+        })
+    }
+}
+
+impl FromStr for DateRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, Utc::now().with_timezone(&local_tz()))
+    }
+}
+
+/// `None` if `s` is not relative at all, `Some(None)` if it goes out of range.
+fn parse_relative(s: &str, now: DateTime<Tz>) -> Option<Option<DateTime<Tz>>> {
+    let unit = s.chars().last()?;
+    let n = s[..s.len() - unit.len_utf8()].parse::<u32>().ok()?;
+    Some(match unit {
+        'h' => now.checked_sub_signed(TimeDelta::hours(n.into())),
+        'd' => now.checked_sub_days(Days::new(n.into())),
+        'w' => now.checked_sub_days(Days::new(u64::from(n) * 7)),
+        'm' => now.checked_sub_months(Months::new(n)),
+        'y' => now.checked_sub_months(Months::new(n.checked_mul(12)?)),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod date_test {
+    use super::*;
+
+    fn now() -> DateTime<Tz> {
+        chrono_tz::Asia::Shanghai
+            .with_ymd_and_hms(2024, 3, 31, 12, 0, 0)
+            .unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn absolute_test() {
+        assert_eq!(
+            DateRange::parse("2024-01-31", now()).unwrap(),
+            DateRange {
+                start: utc("2024-01-30T16:00:00Z"),
+                end: utc("2024-01-31T16:00:00Z"),
+            }
+        );
+        assert_eq!(
+            DateRange::parse("2024-02", now()).unwrap(),
+            DateRange {
+                start: utc("2024-01-31T16:00:00Z"),
+                end: utc("2024-02-29T16:00:00Z"),
+            }
+        );
+        assert_eq!(
+            DateRange::parse("2023", now()).unwrap().end,
+            utc("2023-12-31T16:00:00Z")
+        );
+        assert_eq!(
+            DateRange::parse("2024-01-31T08:30", now()).unwrap().start,
+            utc("2024-01-31T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn relative_test() {
+        let week = DateRange::parse("1w", now()).unwrap();
+        assert_eq!(week.start, utc("2024-03-24T04:00:00Z"));
+        assert_eq!(week.start, week.end);
+        assert_eq!(
+            DateRange::parse("1m", now()).unwrap().start,
+            utc("2024-02-29T04:00:00Z")
+        );
+        assert_eq!(
+            DateRange::parse("12h", now()).unwrap().start,
+            utc("2024-03-30T16:00:00Z")
+        );
+    }
+
+    #[test]
+    fn invalid_test() {
+        assert!(DateRange::parse("yesterday", now()).is_err());
+        assert!(DateRange::parse("2024-13", now()).is_err());
+        assert!(DateRange::parse("7x", now()).is_err());
+    }
+}
@@ -1,25 +1,137 @@
-use meilisearch_sdk::{
-    search::{SearchResults, Selectors},
-    Client,
-    Error::Meilisearch,
-    ErrorCode::DocumentNotFound,
-    MeilisearchError, TaskInfo,
-};
-use serde::{de::DeserializeOwned, Serialize};
+mod embedded;
+mod meilisearch;
+mod memory;
+mod migration;
+mod sqlite;
+mod tantivy;
+
+use std::{ops::Deref, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use teloxide::types::ChatId;
 
-use crate::types::*;
+use crate::{query::Expr, types::*};
 
-const GET_LIMIT: usize = 100;
+pub use self::{
+    meilisearch::Meilisearch, memory::Memory, migration::SCHEMA_VERSION, sqlite::Sqlite,
+    tantivy::Tantivy,
+};
+
+pub(crate) const GET_LIMIT: usize = 100;
 pub const INLINE_REPLY_LIMIT: usize = 20;
+pub const SENDER_SUGGESTION_LIMIT: usize = 5;
+
+#[derive(Clone)]
+pub struct Db(Arc<dyn SearchBackend>);
 
-pub struct Db(pub Client);
+#[derive(Debug)]
+pub enum DbError {
+    Meilisearch(meilisearch_sdk::Error),
+    Tantivy(::tantivy::TantivyError),
+    Sqlite(rusqlite::Error),
+    Json(serde_json::Error),
+    /// The backend is missing or has invalid configuration.
+    Config(String),
+    /// The stored schema is older than this build and needs `migrate`.
+    OutdatedSchema(u32),
+    /// The stored schema was written by a newer build.
+    UnsupportedSchema(u32),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
 
-pub trait Insertable: Serialize {
+pub trait Insertable: Serialize + Sized {
     const INDEX: &'static str;
     const KEY: Option<&'static str>;
 
-    fn init(db: &Db) -> impl std::future::Future<Output = ()> + Send;
+    fn documents(docs: &[Self]) -> Documents<'_>;
+}
+
+pub enum Documents<'a> {
+    Messages(&'a [Message]),
+    Chats(&'a [Chat]),
+    Senders(&'a [Sender]),
+    Topics(&'a [Topic]),
+}
+
+pub struct SearchHit {
+    pub result: Message,
+    pub formatted_text: String,
+}
+
+#[async_trait]
+pub trait SearchBackend: Send + Sync {
+    async fn init(&self) -> DbResult<()>;
+
+    async fn search_message_with_filter(
+        &self,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: Option<usize>,
+    ) -> DbResult<Vec<SearchHit>>;
+
+    async fn insert_chat_with_id(&self, id: ChatId) -> DbResult<()> {
+        self.insert_documents(Documents::Chats(&[Chat::from(id)]))
+            .await
+    }
+
+    async fn delete_chat_with_id(&self, id: ChatId) -> DbResult<()>;
+
+    async fn filter_chat_with_id(&self, id: ChatId) -> DbResult<Option<Chat>>;
+
+    async fn get_all_chats(&self) -> DbResult<Vec<ChatId>>;
+
+    async fn get_sender_name(&self, id: ChatId) -> DbResult<Option<String>>;
+
+    /// Senders whose name or username resembles `name`, best match first, at most
+    /// `SENDER_SUGGESTION_LIMIT` of them.
+    async fn search_senders(&self, name: &str) -> DbResult<Vec<Sender>>;
+
+    /// Forum topics of a chat that were seen or configured.
+    async fn get_topics(&self, chat: ChatId) -> DbResult<Vec<Topic>>;
+
+    async fn get_topic(&self, chat: ChatId, thread_id: i32) -> DbResult<Option<Topic>> {
+        Ok(self
+            .get_topics(chat)
+            .await?
+            .into_iter()
+            .find(|t| t.thread_id == thread_id))
+    }
+
+    /// Lists stored messages, of a single chat if given, in a stable order for jobs walking
+    /// the whole index.
+    async fn get_messages(
+        &self,
+        chat: Option<ChatId>,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<Message>>;
+
+    async fn get_message(&self, key: &str) -> DbResult<Option<Message>>;
+
+    /// Message `id` of `chat` along with up to `n` stored messages right before and after it,
+    /// in order. Web pages and earlier versions of edited messages are left out.
+    async fn get_surrounding_messages(
+        &self,
+        chat: ChatId,
+        id: i32,
+        n: usize,
+    ) -> DbResult<Vec<Message>>;
+
+    async fn delete_messages(&self, keys: &[String]) -> DbResult<()>;
+
+    async fn get_schema_version(&self) -> DbResult<Option<u32>>;
+
+    async fn set_schema_version(&self, version: u32) -> DbResult<()>;
+
+    async fn insert_documents(&self, docs: Documents<'_>) -> DbResult<()>;
+
+    /// Same as `insert_documents`, but only returns once the documents are searchable.
+    async fn insert_documents_and_wait(&self, docs: Documents<'_>) -> DbResult<()> {
+        self.insert_documents(docs).await
+    }
 }
 
 pub enum FilterOption<'a, T> {
@@ -34,135 +146,174 @@ pub enum EnableOption {
     Disable,
 }
 
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+pub enum SortOrder {
+    /// Best matches first, newer ones first among equally good matches
+    #[default]
+    Relevance,
+    /// Newest matches first
+    Newest,
+    /// Oldest matches first
+    Oldest,
+}
+
 pub struct Filter<'a> {
     pub chats: Vec<Chat>,
     pub include_bots: FilterOption<'a, String>,
     pub only_bots: FilterOption<'a, String>,
     pub urls: EnableOption,
+    /// Only messages sent at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only messages sent before this time.
+    pub until: Option<DateTime<Utc>>,
+    pub sender: Option<ChatId>,
+    /// Only messages in these forum topics, as chat and thread ids.
+    pub topics: Option<Vec<(ChatId, i32)>>,
+    /// Only messages of these kinds.
+    pub kinds: Option<Vec<MessageKind>>,
+    /// Only messages forwarded from this user or chat.
+    pub forwarded_from: Option<ChatId>,
+    /// Only replies to this message, as chat and message id.
+    pub replies_to: Option<(ChatId, i32)>,
+    /// Also match earlier versions of edited messages.
+    pub history: bool,
+    pub sort: SortOrder,
 }
 
 impl Db {
-    pub fn new() -> Self {
-        Db(Client::new(
-            std::env::var("MEILISEARCH_HOST").unwrap(),
-            std::env::var("MEILISEARCH_API_KEY").ok(),
-        ))
+    /// Builds the backend selected by `DB_BACKEND`, which defaults to `meilisearch`. Build it
+    /// once and share the clones, they all use the same client.
+    pub fn from_env() -> DbResult<Self> {
+        Ok(match std::env::var("DB_BACKEND").as_deref() {
+            Ok("meilisearch") | Err(_) => Self::from_backend(Meilisearch::from_env()?),
+            Ok("tantivy") => Self::from_backend(Tantivy::from_env()?),
+            Ok("sqlite") => Self::from_backend(Sqlite::from_env()?),
+            Ok("memory") => Self::from_backend(Memory::default()),
+            Ok(b) => return Err(DbError::Config(format!("unknown DB_BACKEND {b}"))),
+        })
     }
 
-    pub async fn init(self) {
-        <Message as Insertable>::init(&self).await;
-        <Chat as Insertable>::init(&self).await;
-        <Sender as Insertable>::init(&self).await;
+    /// Builds the backend from the environment, creates its indexes and checks that the stored
+    /// schema is the one this build expects.
+    pub async fn open() -> DbResult<Self> {
+        let db = Self::from_env()?;
+        db.init().await?;
+        db.check_schema_version().await?;
+        Ok(db)
     }
 
-    pub async fn search_message_with_filter(
-        self,
-        text: &String,
-        filter: &Filter<'_>,
-        offset: Option<usize>,
-    ) -> SearchResults<Message> {
-        log::debug!("search message with filter {}", filter.render());
-        self.0
-            .index(Message::INDEX)
-            .search()
-            .with_limit(INLINE_REPLY_LIMIT)
-            .with_offset(offset.unwrap_or_default())
-            .with_query(text)
-            .with_filter(&filter.render())
-            .with_attributes_to_crop(Selectors::Some(&[("text", None)]))
-            .with_crop_length(match check_contain_utf8(text) {
-                true => 15,
-                false => 6,
-            })
-            .execute::<Message>()
-            .await
-            .unwrap()
+    pub fn from_backend(backend: impl SearchBackend + 'static) -> Self {
+        Db(Arc::new(backend))
     }
 
-    pub async fn insert_chat_with_id(self, id: ChatId) {
-        self.insert(&vec![Chat::from(id)]).await;
+    pub async fn insert<T>(&self, docs: &[T]) -> DbResult<()>
+    where
+        T: Insertable,
+    {
+        if docs.is_empty() {
+            return Ok(());
+        }
+        self.0.insert_documents(T::documents(docs)).await
     }
 
-    pub async fn delete_chat_with_id(self, id: ChatId) {
-        self.0.index(Chat::INDEX).delete_document(id).await.unwrap();
+    pub async fn insert_and_wait<T>(&self, docs: &[T]) -> DbResult<()>
+    where
+        T: Insertable,
+    {
+        if docs.is_empty() {
+            return Ok(());
+        }
+        self.0.insert_documents_and_wait(T::documents(docs)).await
     }
+}
 
-    pub async fn filter_chat_with_id(self, id: ChatId) -> Option<Chat> {
-        self.get_one_document(Chat::INDEX, id.to_string().as_str())
-            .await
-    }
+impl Deref for Db {
+    type Target = dyn SearchBackend;
 
-    pub async fn get_all_chats(self) -> Vec<ChatId> {
-        let mut res: Vec<ChatId> = Vec::new();
-        let index = self.0.index(Chat::INDEX);
-        let mut query = index.search().with_limit(GET_LIMIT).build();
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
 
-        let mut offset: usize = 0;
-        loop {
-            let query_res = query.with_offset(offset).execute::<Chat>().await.unwrap();
-            if query_res.hits.is_empty() {
-                break;
-            }
-            res.append(
-                &mut query_res
-                    .hits
-                    .iter()
-                    .map(|c| c.result.id)
-                    .collect::<Vec<_>>(),
-            );
-            offset += GET_LIMIT;
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Meilisearch(e) => write!(f, "meilisearch: {e}"),
+            DbError::Tantivy(e) => write!(f, "tantivy: {e}"),
+            DbError::Sqlite(e) => write!(f, "sqlite: {e}"),
+            DbError::Json(e) => write!(f, "malformed document: {e}"),
+            DbError::Config(e) => write!(f, "{e}"),
+            DbError::OutdatedSchema(v) => write!(
+                f,
+                "schema version {v} is outdated, run `migrate` to upgrade it to {SCHEMA_VERSION}"
+            ),
+            DbError::UnsupportedSchema(v) => write!(
+                f,
+                "schema version {v} is newer than {SCHEMA_VERSION}, the latest one this build supports"
+            ),
         }
-
-        res
     }
+}
 
-    pub async fn get_sender_name(self, id: ChatId) -> Option<String> {
-        self.get_one_document(Sender::INDEX, id.to_string().as_str())
-            .await
-            .map(|s: Sender| s.name)
-    }
+impl std::error::Error for DbError {}
 
-    async fn get_one_document<T>(self, index: &str, key: &str) -> Option<T>
-    where
-        T: DeserializeOwned + 'static,
-    {
-        match self.0.index(index).get_document(key).await {
-            Ok(d) => Some(d),
-            Err(Meilisearch(MeilisearchError {
-                error_code: DocumentNotFound,
-                ..
-            })) => None,
-            Err(e) => panic!("{e}"),
+impl DbError {
+    /// Whether the error is about the documents themselves, so that retrying them can never
+    /// succeed. Anything else, such as the backend being unreachable, may go away on its own.
+    pub fn is_permanent(&self) -> bool {
+        use ::tantivy::TantivyError;
+        use meilisearch_sdk::errors::{Error as MeiliError, ErrorCode, ErrorType};
+        use rusqlite::ErrorCode as SqliteCode;
+
+        match self {
+            DbError::Meilisearch(MeiliError::Meilisearch(e)) => {
+                e.error_type == ErrorType::InvalidRequest
+                    && e.error_code != ErrorCode::IndexNotFound
+            }
+            DbError::Tantivy(e) => matches!(
+                e,
+                TantivyError::InvalidArgument(_)
+                    | TantivyError::SchemaError(_)
+                    | TantivyError::FieldNotFound(_)
+            ),
+            DbError::Sqlite(e) => {
+                matches!(e, rusqlite::Error::ToSqlConversionFailure(_))
+                    || matches!(
+                        e.sqlite_error_code(),
+                        Some(
+                            SqliteCode::ConstraintViolation
+                                | SqliteCode::TooBig
+                                | SqliteCode::TypeMismatch
+                        )
+                    )
+            }
+            DbError::Json(_) => true,
+            _ => false,
         }
     }
+}
 
-    pub async fn insert<T>(self, docs: &Vec<T>) -> Option<TaskInfo>
-    where
-        T: Insertable,
-    {
-        self.insert_documents(T::INDEX, docs, T::KEY).await
+impl From<meilisearch_sdk::Error> for DbError {
+    fn from(e: meilisearch_sdk::Error) -> Self {
+        DbError::Meilisearch(e)
     }
+}
 
-    async fn insert_documents<T>(
-        self,
-        index: &str,
-        docs: &Vec<T>,
-        key: Option<&str>,
-    ) -> Option<TaskInfo>
-    where
-        T: Serialize,
-    {
-        if docs.is_empty() {
-            return None;
-        }
-        log::debug!("{}", serde_json::to_string_pretty(docs).unwrap());
-        Some(self.0.index(index).add_documents(docs, key).await.unwrap())
+impl From<::tantivy::TantivyError> for DbError {
+    fn from(e: ::tantivy::TantivyError) -> Self {
+        DbError::Tantivy(e)
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
     }
 }
 
-impl Default for Db {
-    fn default() -> Self {
-        Self::new()
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Json(e)
     }
 }
 
@@ -170,32 +321,8 @@ impl Insertable for Message {
     const INDEX: &'static str = "messages";
     const KEY: Option<&'static str> = Some("key");
 
-    async fn init(db: &Db) {
-        let client = &db.0;
-        client.create_index(Self::INDEX, Self::KEY).await.unwrap();
-        client
-            .index(Self::INDEX)
-            .set_searchable_attributes(&["text"])
-            .await
-            .unwrap();
-        client
-            .index(Self::INDEX)
-            .set_filterable_attributes(&["chat_id", "via_bot", "web_page"])
-            .await
-            .unwrap();
-        client
-            .index(Self::INDEX)
-            .set_ranking_rules([
-                "words",
-                "typo",
-                "proximity",
-                "attribute",
-                "sort",
-                "exactness",
-                "date:desc",
-            ])
-            .await
-            .unwrap();
+    fn documents(docs: &[Self]) -> Documents<'_> {
+        Documents::Messages(docs)
     }
 }
 
@@ -203,14 +330,8 @@ impl Insertable for Chat {
     const INDEX: &'static str = "chats";
     const KEY: Option<&'static str> = Some("id");
 
-    async fn init(db: &Db) {
-        let client = &db.0;
-        client.create_index(Self::INDEX, Self::KEY).await.unwrap();
-        client
-            .index(Self::INDEX)
-            .set_searchable_attributes(Vec::<String>::new())
-            .await
-            .unwrap();
+    fn documents(docs: &[Self]) -> Documents<'_> {
+        Documents::Chats(docs)
     }
 }
 
@@ -218,42 +339,62 @@ impl Insertable for Sender {
     const INDEX: &'static str = "senders";
     const KEY: Option<&'static str> = Some("id");
 
-    async fn init(db: &Db) {
-        let client = &db.0;
-        client.create_index(Self::INDEX, Self::KEY).await.unwrap();
-        client
-            .index(Self::INDEX)
-            .set_searchable_attributes(Vec::<String>::new())
-            .await
-            .unwrap();
+    fn documents(docs: &[Self]) -> Documents<'_> {
+        Documents::Senders(docs)
+    }
+}
+
+impl Insertable for Topic {
+    const INDEX: &'static str = "topics";
+    const KEY: Option<&'static str> = Some("key");
+
+    fn documents(docs: &[Self]) -> Documents<'_> {
+        Documents::Topics(docs)
     }
 }
 
 impl Filter<'_> {
-    fn render(&self) -> String {
-        format!(
-            "chat_id IN {:?}{}{}{}",
-            self.chats,
-            match &self.include_bots {
-                FilterOption::Some(x) => format!(" AND (via_bot NOT EXISTS OR via_bot IN {:?})", x),
-                FilterOption::All => "".to_string(),
-                FilterOption::None => " AND via_bot NOT EXISTS".to_string(),
-            },
-            match &self.only_bots {
-                FilterOption::Some(x) => format!(" AND via_bot IN {:?}", x),
-                FilterOption::All => " AND via_bot EXISTS".to_string(),
-                FilterOption::None => "".to_string(),
-            },
-            match self.urls {
-                EnableOption::All => " AND web_page EXISTS".to_string(),
-                EnableOption::Enable => String::default(),
-                EnableOption::Disable => " AND web_page NOT EXISTS".to_string(),
+    /// Evaluates the filter against a single message, for backends that cannot express it
+    /// natively.
+    pub fn matches(&self, m: &Message) -> bool {
+        self.chats.iter().any(|c| c.id == m.chat_id)
+            && match &self.include_bots {
+                FilterOption::Some(x) => m.via_bot.as_ref().is_none_or(|b| x.contains(b)),
+                FilterOption::All => true,
+                FilterOption::None => m.via_bot.is_none(),
+            }
+            && match &self.only_bots {
+                FilterOption::Some(x) => m.via_bot.as_ref().is_some_and(|b| x.contains(b)),
+                FilterOption::All => m.via_bot.is_some(),
+                FilterOption::None => true,
             }
-        )
+            && match self.urls {
+                EnableOption::All => m.web_page.is_some(),
+                EnableOption::Enable => true,
+                EnableOption::Disable => m.web_page.is_none(),
+            }
+            && self.since.is_none_or(|t| m.date >= t)
+            && self.until.is_none_or(|t| m.date < t)
+            && self.sender.is_none_or(|s| m.sender == Some(s))
+            && self.topics.as_ref().is_none_or(|t| {
+                m.thread_id
+                    .is_some_and(|thread_id| t.contains(&(m.chat_id, thread_id)))
+            })
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|k| k.contains(&m.kind.unwrap_or(MessageKind::Text)))
+            && self
+                .forwarded_from
+                .is_none_or(|f| m.forward_from_id == Some(f))
+            && self
+                .replies_to
+                .is_none_or(|(c, r)| m.chat_id == c && m.reply_to == Some(r))
+            && (self.history || m.revised.is_none())
     }
 }
 
-fn check_contain_utf8(s: &String) -> bool {
+fn check_contain_utf8(s: &str) -> bool {
     for b in s.as_bytes() {
         if *b > 127 {
             return true;
@@ -0,0 +1,470 @@
+use std::{cmp::Ordering, collections::HashSet, future::Future};
+
+use super::*;
+use crate::normalize::variant_tokens;
+
+pub(super) struct Token {
+    pub text: String,
+    pub offset_from: usize,
+    pub offset_to: usize,
+    pub position: usize,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x11FF     // Hangul Jamo
+        | 0x2E80..=0x2FDF   // CJK Radicals
+        | 0x3040..=0x30FF   // Hiragana, Katakana
+        | 0x3130..=0x318F   // Hangul Compatibility Jamo
+        | 0x31F0..=0x31FF   // Katakana Phonetic Extensions
+        | 0x3400..=0x4DBF   // CJK Unified Ideographs Extension A
+        | 0x4E00..=0x9FFF   // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF   // Hangul Syllables
+        | 0xF900..=0xFAFF   // CJK Compatibility Ideographs
+        | 0xFF66..=0xFF9F   // Halfwidth Katakana
+        | 0x20000..=0x3134F // CJK Unified Ideographs Extension B..G
+    )
+}
+
+/// Splits `text` into runs of CJK characters and runs of other alphanumeric characters,
+/// dropping everything else.
+fn runs(text: &str) -> Vec<(bool, Vec<(usize, char)>)> {
+    let mut res: Vec<(bool, Vec<(usize, char)>)> = vec![];
+    for (i, c) in text.char_indices() {
+        let cjk = is_cjk(c);
+        if !cjk && !c.is_alphanumeric() {
+            res.push((false, vec![]));
+            continue;
+        }
+        match res.last_mut() {
+            Some((last_cjk, run)) if *last_cjk == cjk && !run.is_empty() => run.push((i, c)),
+            _ => res.push((cjk, vec![(i, c)])),
+        }
+    }
+    res.retain(|(_, run)| !run.is_empty());
+    res
+}
+
+fn char_end(i: usize, c: char) -> usize {
+    i + c.len_utf8()
+}
+
+/// Tokens stored in the index: lowercase words, plus every CJK character on its own and
+/// paired with the next one.
+pub(super) fn index_tokens(text: &str) -> Vec<Token> {
+    let mut res = vec![];
+    let mut position = 0;
+    for (cjk, run) in runs(text) {
+        if !cjk {
+            let (from, _) = run[0];
+            let (i, c) = run[run.len() - 1];
+            res.push(Token {
+                text: text[from..char_end(i, c)].to_lowercase(),
+                offset_from: from,
+                offset_to: char_end(i, c),
+                position,
+            });
+            position += 1;
+            continue;
+        }
+        for (k, &(i, c)) in run.iter().enumerate() {
+            res.push(Token {
+                text: c.to_string(),
+                offset_from: i,
+                offset_to: char_end(i, c),
+                position,
+            });
+            if let Some(&(j, d)) = run.get(k + 1) {
+                res.push(Token {
+                    text: text[i..char_end(j, d)].to_string(),
+                    offset_from: i,
+                    offset_to: char_end(j, d),
+                    position,
+                });
+            }
+            position += 1;
+        }
+    }
+    res
+}
+
+/// Tokens that all need to be present in a matching message: lowercase words and CJK
+/// bigrams, or the character itself for a lone CJK character.
+pub(super) fn query_tokens(text: &str) -> Vec<String> {
+    let mut res = vec![];
+    for (cjk, run) in runs(text) {
+        let (from, _) = run[0];
+        let (i, c) = run[run.len() - 1];
+        if !cjk {
+            res.push(text[from..char_end(i, c)].to_lowercase());
+        } else if run.len() == 1 {
+            res.push(c.to_string());
+        } else {
+            res.extend(
+                run.windows(2)
+                    .map(|w| text[w[0].0..char_end(w[1].0, w[1].1)].to_string()),
+            );
+        }
+    }
+    res.dedup();
+    res
+}
+
+/// Query tokens of every word and phrase a match may contain.
+pub(super) fn expr_tokens(query: &Expr) -> Vec<String> {
+    query_tokens(&query.terms().join(" "))
+}
+
+/// Ways of matching the word `t`, each a set of tokens that all need to be present: its own
+/// tokens and, for an ASCII word, the pinyin or romaji it may have been typed as.
+pub(super) fn term_alternatives(t: &str) -> Vec<Vec<String>> {
+    let mut res = vec![query_tokens(t)];
+    if !check_contain_utf8(t) {
+        res.extend(variant_tokens(t));
+    }
+    res
+}
+
+/// The tokens of a word whose every way of matching is a single token, so that a message
+/// matches it exactly when the index holds one of them. Only such words can be excluded in
+/// the index, excluding the tokens of anything else would also drop messages that contain
+/// them without matching.
+pub(super) fn exact_tokens(query: &Expr) -> Option<Vec<String>> {
+    match query {
+        Expr::Term(t) => term_alternatives(t)
+            .into_iter()
+            .map(|a| match a.as_slice() {
+                [t] => Some(t.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Tokens stored in the index for `m`, its variants included.
+pub(super) fn message_tokens(m: &Message) -> Vec<String> {
+    index_tokens(m.search_text())
+        .into_iter()
+        .map(|t| t.text)
+        .chain(
+            m.variants
+                .iter()
+                .flat_map(|v| v.split(' ').map(ToString::to_string)),
+        )
+        .collect()
+}
+
+/// Evaluates `query` against `m` with the same tokens the index uses. Phrases need to
+/// appear as they are, ignoring case and spacing.
+pub(super) fn expr_matches(query: &Expr, m: &Message) -> bool {
+    fn eval(query: &Expr, text: &str, tokens: &HashSet<String>) -> bool {
+        match query {
+            Expr::Term(t) => term_alternatives(t)
+                .iter()
+                .any(|a| a.iter().all(|t| tokens.contains(t))),
+            Expr::Phrase(p) => text.contains(&p.to_lowercase()),
+            Expr::Not(e) => !eval(e, text, tokens),
+            Expr::And(v) => v.iter().all(|e| eval(e, text, tokens)),
+            Expr::Or(v) => v.iter().any(|e| eval(e, text, tokens)),
+        }
+    }
+
+    let text = m
+        .search_text()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let tokens = message_tokens(m).into_iter().collect();
+    eval(query, &text.to_lowercase(), &tokens)
+}
+
+/// Crops `text` to `crop_length` words around the first match of `query` in `search_text`,
+/// which is `text` or its normalized form, the way Meilisearch's `attributesToCrop` does.
+/// Every CJK character counts as a word.
+pub(super) fn crop(text: &str, search_text: &str, query: &[String], crop_length: usize) -> String {
+    let words = runs(text)
+        .into_iter()
+        .flat_map(|(cjk, run)| match cjk {
+            true => run
+                .into_iter()
+                .map(|(i, c)| (i, char_end(i, c)))
+                .collect::<Vec<_>>(),
+            false => vec![(
+                run[0].0,
+                char_end(run[run.len() - 1].0, run[run.len() - 1].1),
+            )],
+        })
+        .collect::<Vec<_>>();
+    if words.len() <= crop_length {
+        return text.to_string();
+    }
+
+    let query = query.iter().collect::<HashSet<_>>();
+    let first_match = index_tokens(search_text)
+        .into_iter()
+        .find(|t| query.contains(&t.text))
+        .and_then(|t| words.iter().position(|w| w.0 == t.offset_from))
+        .unwrap_or_default();
+    let start = first_match
+        .saturating_sub((crop_length - 1) / 2)
+        .min(words.len() - crop_length);
+    let end = start + crop_length;
+
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        &text[words[start].0..words[end - 1].1],
+        if end < words.len() { "…" } else { "" }
+    )
+}
+
+/// Orders `candidates` the way `filter.sort` wants, for backends that cannot sort natively.
+pub(super) fn sort_candidates(candidates: &mut [(f32, Message)], sort: SortOrder) {
+    candidates.sort_by(|(a_score, a), (b_score, b)| match sort {
+        SortOrder::Relevance => b_score
+            .partial_cmp(a_score)
+            .unwrap_or(Ordering::Equal)
+            .then(b.date.cmp(&a.date)),
+        SortOrder::Newest => b.date.cmp(&a.date),
+        SortOrder::Oldest => a.date.cmp(&b.date),
+    });
+}
+
+/// Walks the candidates in `filter.sort` order, fetched `GET_LIMIT` at a time as
+/// `(offset, limit)`, applies `query` and `filter` to them and returns the page at `offset`
+/// with cropped text. Backends push as much of both into `fetch` as they can, this only
+/// checks the rest.
+pub(super) async fn paginate<F, Fut>(
+    mut fetch: F,
+    query: &Expr,
+    filter: &Filter<'_>,
+    offset: Option<usize>,
+) -> DbResult<Vec<SearchHit>>
+where
+    F: FnMut(usize, usize) -> Fut,
+    Fut: Future<Output = DbResult<Vec<Message>>>,
+{
+    let tokens = expr_tokens(query);
+    let crop_length = match check_contain_utf8(&query.terms().concat()) {
+        true => 15,
+        false => 6,
+    };
+
+    let mut skip = offset.unwrap_or_default();
+    let mut res = vec![];
+    for candidate_offset in (0..).step_by(GET_LIMIT) {
+        let candidates = fetch(candidate_offset, GET_LIMIT).await?;
+        let done = candidates.len() < GET_LIMIT;
+        for m in candidates {
+            if !filter.matches(&m) || !expr_matches(query, &m) {
+                continue;
+            } else if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            res.push(SearchHit {
+                formatted_text: crop(&m.text, m.search_text(), &tokens, crop_length),
+                result: m,
+            });
+            if res.len() == INLINE_REPLY_LIMIT {
+                return Ok(res);
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    Ok(res)
+}
+
+/// Keeps the senders whose name or username resembles `name` and orders them by how well
+/// they match, tolerating a typo for every four characters like Meilisearch does.
+pub(super) fn rank_senders(senders: impl IntoIterator<Item = Sender>, name: &str) -> Vec<Sender> {
+    let name = name.trim_start_matches('@').to_lowercase();
+    let max_typos = name.chars().count() / 4;
+    let score = |s: &Sender| {
+        std::iter::once(&s.name)
+            .chain(&s.username)
+            .map(|n| n.to_lowercase())
+            .filter_map(|n| {
+                if n == name {
+                    Some(0)
+                } else if n.starts_with(&name) {
+                    Some(1)
+                } else if n.contains(&name) {
+                    Some(2)
+                } else {
+                    n.split_whitespace()
+                        .map(|w| edit_distance(w, &name))
+                        .min()
+                        .filter(|&d| d <= max_typos)
+                        .map(|d| 2 + d)
+                }
+            })
+            .min()
+    };
+
+    let mut res = senders
+        .into_iter()
+        .filter_map(|s| score(&s).map(|k| (k, s)))
+        .collect::<Vec<_>>();
+    res.sort_by(|(a_score, a), (b_score, b)| a_score.cmp(b_score).then(a.name.cmp(&b.name)));
+    res.into_iter()
+        .map(|(_, s)| s)
+        .take(SENDER_SUGGESTION_LIMIT)
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let next = (diagonal + usize::from(x != y))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+pub(super) mod embedded_test {
+    use super::*;
+
+    pub fn message(id: i32, text: &str, date: i64) -> Message {
+        Message {
+            key: format!("-1001_{id}"),
+            text: text.to_string(),
+            normalized: None,
+            variants: None,
+            from: None,
+            sender: Some(ChatId(1)),
+            via_bot: None,
+            id,
+            chat_id: ChatId(-1001),
+            thread_id: None,
+            topic: None,
+            reply_to: None,
+            reply_snippet: None,
+            forward_from: None,
+            forward_from_id: None,
+            forward_link: None,
+            photo_file_id: None,
+            kind: None,
+            web_page: None,
+            thumbnail_url: None,
+            date: chrono::DateTime::from_timestamp(date, 0).unwrap(),
+            timestamp: date,
+            edit_date: None,
+            original: None,
+            revised: None,
+        }
+    }
+
+    pub fn query(s: &str) -> Expr {
+        Expr::parse(s).unwrap()
+    }
+
+    pub fn filter(chats: &[i64]) -> Filter<'static> {
+        Filter {
+            chats: chats.iter().map(|&c| Chat::from(ChatId(c))).collect(),
+            include_bots: FilterOption::All,
+            only_bots: FilterOption::None,
+            urls: EnableOption::Enable,
+            since: None,
+            until: None,
+            sender: None,
+            topics: None,
+            kinds: None,
+            forwarded_from: None,
+            replies_to: None,
+            history: false,
+            sort: SortOrder::Relevance,
+        }
+    }
+
+    #[test]
+    fn query_tokens_test() {
+        assert_eq!(query_tokens("发展 Rust"), vec!["发展", "rust"]);
+        assert_eq!(query_tokens("经济发展"), vec!["经济", "济发", "发展"]);
+        assert_eq!(query_tokens("发"), vec!["发"]);
+    }
+
+    #[test]
+    fn index_tokens_test() {
+        let tokens = index_tokens("Hi, 发展!")
+            .into_iter()
+            .map(|t| t.text)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec!["hi", "发", "发展", "展"]);
+    }
+
+    #[test]
+    fn rank_senders_test() {
+        let sender = |id, name: &str, username: Option<&str>| Sender {
+            id: ChatId(id),
+            name: name.to_string(),
+            username: username.map(ToString::to_string),
+        };
+        let senders = [
+            sender(1, "Alice Liddell", Some("alice")),
+            sender(2, "Alicia Keys", None),
+            sender(3, "张三", None),
+        ];
+        let ids = |name| {
+            rank_senders(senders.clone(), name)
+                .into_iter()
+                .map(|s| s.id.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("@alice"), vec![1]);
+        assert_eq!(ids("ali"), vec![1, 2]);
+        assert_eq!(ids("alcia"), vec![2]);
+        assert_eq!(ids("张"), vec![3]);
+        assert!(ids("bob").is_empty());
+    }
+
+    #[test]
+    fn expr_matches_test() {
+        let m = message(1, "Hello  World, 经济发展很快", 1);
+        assert!(expr_matches(&query(r#""hello world" 发展"#), &m));
+        assert!(!expr_matches(&query(r#""world hello""#), &m));
+        assert!(!expr_matches(&query("经济 -发展"), &m));
+        assert!(expr_matches(&query("(天气 OR 经济) 很快"), &m));
+        assert!(expr_matches(&query(""), &m));
+    }
+
+    #[test]
+    fn variants_matches_test() {
+        use crate::normalize::{Normalizer, Step};
+
+        let n = Normalizer::new(vec![Step::Pinyin, Step::Japanese, Step::Korean]);
+        let text = "经济发展很快 ラーメン 대한민국";
+        let m = Message {
+            variants: n.variants(text),
+            ..message(1, text, 1)
+        };
+        assert!(expr_matches(&query("fazhan"), &m));
+        assert!(expr_matches(&query("jjfz"), &m));
+        assert!(expr_matches(&query("jingjifazhan -tianqi"), &m));
+        assert!(!expr_matches(&query("fazhan"), &message(1, text, 1)));
+        assert!(!expr_matches(&query("fazhanmanman"), &m));
+        assert!(expr_matches(&query("ramen"), &m));
+        assert!(expr_matches(&query("대한민구"), &m));
+        assert!(!expr_matches(&query("대한미구"), &m));
+    }
+
+    #[test]
+    fn crop_test() {
+        let text = "一二三四五六七八九十甲乙丙丁戊己庚辛壬癸";
+        assert_eq!(crop(text, text, &["甲乙".to_string()], 5), "…九十甲乙丙…");
+        assert_eq!(crop(text, text, &["一二".to_string()], 3), "一二三…");
+        assert_eq!(crop("short text", "short text", &[], 6), "short text");
+    }
+}
@@ -0,0 +1,542 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use meilisearch_sdk::{
+    documents::DocumentsQuery,
+    search::{MatchingStrategies, Selectors},
+    Client,
+    Error::Meilisearch as MeilisearchErr,
+    ErrorCode::{DocumentNotFound, IndexNotFound},
+    MeilisearchError, TaskInfo,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use teloxide::types::ChatId;
+
+use super::{embedded::expr_matches, *};
+
+pub struct Meilisearch(pub Client);
+
+const META_INDEX: &str = "meta";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Meilisearch's default `maxTotalHits`, nothing past it can be paged to anyway.
+const SCAN_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct SchemaVersion {
+    id: String,
+    version: u32,
+}
+
+trait Index: Insertable {
+    /// Creates the index and updates its settings, returning the last task enqueued for it.
+    fn init(client: &Client) -> impl std::future::Future<Output = DbResult<TaskInfo>> + Send;
+}
+
+impl Meilisearch {
+    pub fn from_env() -> DbResult<Self> {
+        let host = std::env::var("MEILISEARCH_HOST")
+            .map_err(|_| DbError::Config("MEILISEARCH_HOST is not set".to_string()))?;
+        Ok(Meilisearch(Client::new(
+            host,
+            std::env::var("MEILISEARCH_API_KEY").ok(),
+        )))
+    }
+
+    async fn get_one_document<T>(&self, index: &str, key: &str) -> DbResult<Option<T>>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        match self.0.index(index).get_document(key).await {
+            Ok(d) => Ok(Some(d)),
+            Err(MeilisearchErr(MeilisearchError {
+                error_code: DocumentNotFound | IndexNotFound,
+                ..
+            })) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn insert_typed_documents<T>(&self, docs: &[T]) -> DbResult<TaskInfo>
+    where
+        T: Insertable + Send + Sync,
+    {
+        log::debug!("{}", serde_json::to_string_pretty(docs)?);
+        Ok(self.0.index(T::INDEX).add_documents(docs, T::KEY).await?)
+    }
+
+    async fn search_messages(
+        &self,
+        text: &str,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: usize,
+        limit: usize,
+        strategy: Option<MatchingStrategies>,
+    ) -> DbResult<Vec<SearchHit>> {
+        let filter_expr = filter.render();
+        let index = self.0.index(Message::INDEX);
+        let mut search = index.search();
+        search
+            .with_limit(limit)
+            .with_offset(offset)
+            .with_query(text)
+            .with_filter(&filter_expr)
+            .with_sort(match filter.sort {
+                SortOrder::Relevance => &[],
+                SortOrder::Newest => &["timestamp:desc"],
+                SortOrder::Oldest => &["timestamp:asc"],
+            })
+            .with_attributes_to_crop(Selectors::Some(&[("text", None)]))
+            .with_crop_length(match check_contain_utf8(&query.terms().concat()) {
+                true => 15,
+                false => 6,
+            });
+        if let Some(s) = strategy {
+            search.with_matching_strategy(s);
+        }
+        Ok(search
+            .execute::<Message>()
+            .await?
+            .hits
+            .into_iter()
+            .map(|m| SearchHit {
+                formatted_text: m
+                    .formatted_result
+                    .as_ref()
+                    .and_then(|f| f["text"].as_str())
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| m.result.text.clone()),
+                result: m.result,
+            })
+            .collect())
+    }
+
+    async fn add_documents(&self, docs: Documents<'_>) -> DbResult<TaskInfo> {
+        match docs {
+            Documents::Messages(d) => self.insert_typed_documents(d).await,
+            Documents::Chats(d) => self.insert_typed_documents(d).await,
+            Documents::Senders(d) => self.insert_typed_documents(d).await,
+            Documents::Topics(d) => self.insert_typed_documents(d).await,
+        }
+    }
+}
+
+#[async_trait]
+impl SearchBackend for Meilisearch {
+    async fn init(&self) -> DbResult<()> {
+        // Tasks of an index run in order, so once its last one is done the index is ready to
+        // be read, even on a brand-new instance.
+        for task in [
+            <Message as Index>::init(&self.0).await?,
+            <Chat as Index>::init(&self.0).await?,
+            <Sender as Index>::init(&self.0).await?,
+            <Topic as Index>::init(&self.0).await?,
+            self.0.create_index(META_INDEX, Some("id")).await?,
+        ] {
+            task.wait_for_completion(
+                &self.0,
+                Some(Duration::from_millis(200)),
+                Some(Duration::MAX),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn search_message_with_filter(
+        &self,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: Option<usize>,
+    ) -> DbResult<Vec<SearchHit>> {
+        log::debug!("search message with filter {}", filter.render());
+        let offset = offset.unwrap_or_default();
+        if query.is_simple() {
+            let text = render(&query.branches()[0]);
+            return self
+                .search_messages(&text, query, filter, offset, INLINE_REPLY_LIMIT, None)
+                .await;
+        }
+
+        // There is no OR or NOT in Meilisearch, so every alternative is searched on its own
+        // and the results are checked against the whole query, then merged by rank.
+        let wanted = offset + INLINE_REPLY_LIMIT;
+        let mut hits = HashMap::<String, (usize, SearchHit)>::new();
+        for branch in query.branches() {
+            let text = render(&branch);
+            let (mut scanned, mut found) = (0, 0);
+            while found < wanted && scanned < SCAN_LIMIT {
+                let page = self
+                    .search_messages(
+                        &text,
+                        query,
+                        filter,
+                        scanned,
+                        GET_LIMIT,
+                        Some(MatchingStrategies::ALL),
+                    )
+                    .await?;
+                if page.is_empty() {
+                    break;
+                }
+                for h in page {
+                    if expr_matches(query, &h.result) {
+                        let rank = found;
+                        found += 1;
+                        hits.entry(h.result.key.clone())
+                            .and_modify(|(r, _)| *r = rank.min(*r))
+                            .or_insert((rank, h));
+                    }
+                    scanned += 1;
+                }
+            }
+        }
+
+        let mut hits = hits.into_values().collect::<Vec<_>>();
+        hits.sort_by(|(a_rank, a), (b_rank, b)| match filter.sort {
+            SortOrder::Relevance => a_rank.cmp(b_rank).then(b.result.date.cmp(&a.result.date)),
+            SortOrder::Newest => b.result.date.cmp(&a.result.date),
+            SortOrder::Oldest => a.result.date.cmp(&b.result.date),
+        });
+        Ok(hits
+            .into_iter()
+            .map(|(_, h)| h)
+            .skip(offset)
+            .take(INLINE_REPLY_LIMIT)
+            .collect())
+    }
+
+    async fn delete_chat_with_id(&self, id: ChatId) -> DbResult<()> {
+        self.0.index(Chat::INDEX).delete_document(id).await?;
+        Ok(())
+    }
+
+    async fn filter_chat_with_id(&self, id: ChatId) -> DbResult<Option<Chat>> {
+        self.get_one_document(Chat::INDEX, id.to_string().as_str())
+            .await
+    }
+
+    async fn get_all_chats(&self) -> DbResult<Vec<ChatId>> {
+        let mut res: Vec<ChatId> = Vec::new();
+        let index = self.0.index(Chat::INDEX);
+        let mut query = index.search().with_limit(GET_LIMIT).build();
+
+        let mut offset: usize = 0;
+        loop {
+            let query_res = query.with_offset(offset).execute::<Chat>().await?;
+            if query_res.hits.is_empty() {
+                break;
+            }
+            res.append(
+                &mut query_res
+                    .hits
+                    .iter()
+                    .map(|c| c.result.id)
+                    .collect::<Vec<_>>(),
+            );
+            offset += GET_LIMIT;
+        }
+
+        Ok(res)
+    }
+
+    async fn get_sender_name(&self, id: ChatId) -> DbResult<Option<String>> {
+        Ok(self
+            .get_one_document(Sender::INDEX, id.to_string().as_str())
+            .await?
+            .map(|s: Sender| s.name))
+    }
+
+    async fn search_senders(&self, name: &str) -> DbResult<Vec<Sender>> {
+        Ok(self
+            .0
+            .index(Sender::INDEX)
+            .search()
+            .with_query(name.trim_start_matches('@'))
+            .with_limit(SENDER_SUGGESTION_LIMIT)
+            .execute::<Sender>()
+            .await?
+            .hits
+            .into_iter()
+            .map(|s| s.result)
+            .collect())
+    }
+
+    async fn get_topics(&self, chat: ChatId) -> DbResult<Vec<Topic>> {
+        Ok(self
+            .0
+            .index(Topic::INDEX)
+            .search()
+            .with_filter(&format!("chat_id = {chat}"))
+            .with_limit(SCAN_LIMIT)
+            .execute::<Topic>()
+            .await?
+            .hits
+            .into_iter()
+            .map(|t| t.result)
+            .collect())
+    }
+
+    async fn get_messages(
+        &self,
+        chat: Option<ChatId>,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<Message>> {
+        let index = self.0.index(Message::INDEX);
+        let filter = chat.map(|c| format!("chat_id = {c}"));
+        let mut query = DocumentsQuery::new(&index);
+        query.with_offset(offset).with_limit(limit);
+        if let Some(f) = &filter {
+            query.with_filter(f);
+        }
+        Ok(index.get_documents_with(&query).await?.results)
+    }
+
+    async fn get_message(&self, key: &str) -> DbResult<Option<Message>> {
+        self.get_one_document(Message::INDEX, key).await
+    }
+
+    async fn get_surrounding_messages(
+        &self,
+        chat: ChatId,
+        id: i32,
+        n: usize,
+    ) -> DbResult<Vec<Message>> {
+        let index = self.0.index(Message::INDEX);
+        let plain = format!("chat_id = {chat} AND web_page NOT EXISTS AND revised NOT EXISTS");
+        let (before_filter, after_filter) = (
+            format!("{plain} AND id < {id}"),
+            format!("{plain} AND id >= {id}"),
+        );
+        let before = index
+            .search()
+            .with_filter(&before_filter)
+            .with_sort(&["id:desc"])
+            .with_limit(n)
+            .execute::<Message>()
+            .await?;
+        let after = index
+            .search()
+            .with_filter(&after_filter)
+            .with_sort(&["id:asc"])
+            .with_limit(n + 1)
+            .execute::<Message>()
+            .await?;
+        Ok(before
+            .hits
+            .into_iter()
+            .rev()
+            .chain(after.hits)
+            .map(|h| h.result)
+            .collect())
+    }
+
+    async fn delete_messages(&self, keys: &[String]) -> DbResult<()> {
+        self.0.index(Message::INDEX).delete_documents(keys).await?;
+        Ok(())
+    }
+
+    async fn get_schema_version(&self) -> DbResult<Option<u32>> {
+        Ok(self
+            .get_one_document(META_INDEX, SCHEMA_VERSION_KEY)
+            .await?
+            .map(|v: SchemaVersion| v.version))
+    }
+
+    async fn set_schema_version(&self, version: u32) -> DbResult<()> {
+        self.0
+            .index(META_INDEX)
+            .add_documents(
+                &[SchemaVersion {
+                    id: SCHEMA_VERSION_KEY.to_string(),
+                    version,
+                }],
+                Some("id"),
+            )
+            .await?
+            // Queued behind the migration's own tasks, which can take long on a large index.
+            .wait_for_completion(
+                &self.0,
+                Some(Duration::from_millis(200)),
+                Some(Duration::MAX),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_documents(&self, docs: Documents<'_>) -> DbResult<()> {
+        self.add_documents(docs).await?;
+        Ok(())
+    }
+
+    async fn insert_documents_and_wait(&self, docs: Documents<'_>) -> DbResult<()> {
+        self.add_documents(docs)
+            .await?
+            .wait_for_completion(
+                &self.0,
+                Some(Duration::from_millis(200)),
+                Some(Duration::MAX),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl Index for Message {
+    async fn init(client: &Client) -> DbResult<TaskInfo> {
+        client.create_index(Self::INDEX, Self::KEY).await?;
+        client
+            .index(Self::INDEX)
+            .set_searchable_attributes(&["text", "normalized", "variants"])
+            .await?;
+        client
+            .index(Self::INDEX)
+            .set_filterable_attributes(&[
+                "chat_id",
+                "id",
+                "thread_id",
+                "kind",
+                "revised",
+                "forward_from_id",
+                "reply_to",
+                "via_bot",
+                "web_page",
+                "timestamp",
+                "sender",
+            ])
+            .await?;
+        client
+            .index(Self::INDEX)
+            .set_sortable_attributes(&["timestamp", "id"])
+            .await?;
+        // `sort` goes first so that sorting by date overrides relevance when asked for, it
+        // does nothing otherwise.
+        Ok(client
+            .index(Self::INDEX)
+            .set_ranking_rules([
+                "sort",
+                "words",
+                "typo",
+                "proximity",
+                "attribute",
+                "exactness",
+                "date:desc",
+            ])
+            .await?)
+    }
+}
+
+impl Index for Chat {
+    async fn init(client: &Client) -> DbResult<TaskInfo> {
+        client.create_index(Self::INDEX, Self::KEY).await?;
+        Ok(client
+            .index(Self::INDEX)
+            .set_searchable_attributes(Vec::<String>::new())
+            .await?)
+    }
+}
+
+impl Index for Sender {
+    async fn init(client: &Client) -> DbResult<TaskInfo> {
+        client.create_index(Self::INDEX, Self::KEY).await?;
+        Ok(client
+            .index(Self::INDEX)
+            .set_searchable_attributes(&["name", "username"])
+            .await?)
+    }
+}
+
+impl Index for Topic {
+    async fn init(client: &Client) -> DbResult<TaskInfo> {
+        client.create_index(Self::INDEX, Self::KEY).await?;
+        client
+            .index(Self::INDEX)
+            .set_searchable_attributes(Vec::<String>::new())
+            .await?;
+        Ok(client
+            .index(Self::INDEX)
+            .set_filterable_attributes(&["chat_id"])
+            .await?)
+    }
+}
+
+/// Words and quoted phrases that must all match, in Meilisearch's query syntax.
+fn render(branch: &[&Expr]) -> String {
+    branch
+        .iter()
+        .map(|e| match e {
+            Expr::Phrase(p) => format!("\"{p}\""),
+            e => e.terms().concat(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Filter<'_> {
+    fn render(&self) -> String {
+        format!(
+            "chat_id IN {:?}{}{}{}{}{}{}{}{}{}{}{}",
+            self.chats,
+            match &self.include_bots {
+                FilterOption::Some(x) => format!(" AND (via_bot NOT EXISTS OR via_bot IN {:?})", x),
+                FilterOption::All => "".to_string(),
+                FilterOption::None => " AND via_bot NOT EXISTS".to_string(),
+            },
+            match &self.only_bots {
+                FilterOption::Some(x) => format!(" AND via_bot IN {:?}", x),
+                FilterOption::All => " AND via_bot EXISTS".to_string(),
+                FilterOption::None => "".to_string(),
+            },
+            match self.urls {
+                EnableOption::All => " AND web_page EXISTS".to_string(),
+                EnableOption::Enable => String::default(),
+                EnableOption::Disable => " AND web_page NOT EXISTS".to_string(),
+            },
+            self.since
+                .map(|t| format!(" AND timestamp >= {}", t.timestamp()))
+                .unwrap_or_default(),
+            self.until
+                .map(|t| format!(" AND timestamp < {}", t.timestamp()))
+                .unwrap_or_default(),
+            self.sender
+                .map(|s| format!(" AND sender = {s}"))
+                .unwrap_or_default(),
+            match &self.topics {
+                Some(t) if t.is_empty() => " AND chat_id IN []".to_string(),
+                Some(t) => format!(
+                    " AND ({})",
+                    t.iter()
+                        .map(|(chat, thread)| format!(
+                            "(chat_id = {chat} AND thread_id = {thread})"
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(" OR ")
+                ),
+                None => String::default(),
+            },
+            match &self.kinds {
+                // Plain text messages are stored without a kind.
+                Some(k) => format!(
+                    " AND (kind IN [{}]{})",
+                    k.iter().map(|k| k.name()).collect::<Vec<_>>().join(", "),
+                    match k.contains(&MessageKind::Text) {
+                        true => " OR kind NOT EXISTS",
+                        false => "",
+                    }
+                ),
+                None => String::default(),
+            },
+            self.forwarded_from
+                .map(|f| format!(" AND forward_from_id = {f}"))
+                .unwrap_or_default(),
+            self.replies_to
+                .map(|(c, r)| format!(" AND chat_id = {c} AND reply_to = {r}"))
+                .unwrap_or_default(),
+            match self.history {
+                true => "",
+                false => " AND revised NOT EXISTS",
+            },
+        )
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use teloxide::types::ChatId;
+
+use super::{embedded::*, *};
+
+/// Backend keeping everything in process memory. Nothing survives a restart, so it is meant
+/// for tests and trying the bot out.
+#[derive(Clone, Default)]
+pub struct Memory(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    messages: BTreeMap<String, Message>,
+    chats: BTreeSet<ChatId>,
+    senders: HashMap<ChatId, Sender>,
+    topics: BTreeMap<String, Topic>,
+    schema_version: Option<u32>,
+}
+
+#[async_trait]
+impl SearchBackend for Memory {
+    async fn init(&self) -> DbResult<()> {
+        Ok(())
+    }
+
+    async fn search_message_with_filter(
+        &self,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: Option<usize>,
+    ) -> DbResult<Vec<SearchHit>> {
+        let terms = expr_tokens(query).into_iter().collect::<HashSet<_>>();
+        let mut candidates = self
+            .0
+            .lock()
+            .unwrap()
+            .messages
+            .values()
+            .filter(|m| expr_matches(query, m))
+            .map(|m| {
+                let tokens = message_tokens(m);
+                let hits = tokens.iter().filter(|t| terms.contains(*t)).count();
+                (hits as f32 / tokens.len().max(1) as f32, m.clone())
+            })
+            .collect::<Vec<_>>();
+        sort_candidates(&mut candidates, filter.sort);
+
+        paginate(
+            |offset, limit| {
+                std::future::ready(Ok(candidates
+                    .iter()
+                    .skip(offset)
+                    .take(limit)
+                    .map(|(_, m)| m.clone())
+                    .collect()))
+            },
+            query,
+            filter,
+            offset,
+        )
+        .await
+    }
+
+    async fn delete_chat_with_id(&self, id: ChatId) -> DbResult<()> {
+        self.0.lock().unwrap().chats.remove(&id);
+        Ok(())
+    }
+
+    async fn filter_chat_with_id(&self, id: ChatId) -> DbResult<Option<Chat>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .chats
+            .contains(&id)
+            .then_some(Chat::from(id)))
+    }
+
+    async fn get_all_chats(&self) -> DbResult<Vec<ChatId>> {
+        Ok(self.0.lock().unwrap().chats.iter().copied().collect())
+    }
+
+    async fn get_sender_name(&self, id: ChatId) -> DbResult<Option<String>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .senders
+            .get(&id)
+            .map(|s| s.name.clone()))
+    }
+
+    async fn search_senders(&self, name: &str) -> DbResult<Vec<Sender>> {
+        Ok(rank_senders(
+            self.0.lock().unwrap().senders.values().cloned(),
+            name,
+        ))
+    }
+
+    async fn get_topics(&self, chat: ChatId) -> DbResult<Vec<Topic>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .topics
+            .values()
+            .filter(|t| t.chat_id == chat)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_messages(
+        &self,
+        chat: Option<ChatId>,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<Message>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .messages
+            .values()
+            .filter(|m| chat.is_none_or(|c| c == m.chat_id))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_message(&self, key: &str) -> DbResult<Option<Message>> {
+        Ok(self.0.lock().unwrap().messages.get(key).cloned())
+    }
+
+    async fn get_surrounding_messages(
+        &self,
+        chat: ChatId,
+        id: i32,
+        n: usize,
+    ) -> DbResult<Vec<Message>> {
+        let mut messages = self
+            .0
+            .lock()
+            .unwrap()
+            .messages
+            .values()
+            .filter(|m| m.chat_id == chat && m.web_page.is_none() && m.revised.is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.id);
+        let start = messages.partition_point(|m| m.id < id).saturating_sub(n);
+        let end = (messages.partition_point(|m| m.id < id) + n + 1).min(messages.len());
+        Ok(messages.drain(start..end).collect())
+    }
+
+    async fn delete_messages(&self, keys: &[String]) -> DbResult<()> {
+        let mut state = self.0.lock().unwrap();
+        for k in keys {
+            state.messages.remove(k);
+        }
+        Ok(())
+    }
+
+    async fn get_schema_version(&self) -> DbResult<Option<u32>> {
+        Ok(self.0.lock().unwrap().schema_version)
+    }
+
+    async fn set_schema_version(&self, version: u32) -> DbResult<()> {
+        self.0.lock().unwrap().schema_version = Some(version);
+        Ok(())
+    }
+
+    async fn insert_documents(&self, docs: Documents<'_>) -> DbResult<()> {
+        let mut state = self.0.lock().unwrap();
+        match docs {
+            Documents::Messages(d) => state
+                .messages
+                .extend(d.iter().map(|m| (m.key.clone(), m.clone()))),
+            Documents::Chats(d) => state.chats.extend(d.iter().map(|c| c.id)),
+            Documents::Senders(d) => state.senders.extend(d.iter().map(|s| (s.id, s.clone()))),
+            Documents::Topics(d) => state
+                .topics
+                .extend(d.iter().map(|t| (t.key.clone(), t.clone()))),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use futures::future::BoxFuture;
+
+use super::*;
+use crate::normalize::{normalize, variants};
+
+/// Version of the document shape and index settings this build reads and writes. Bump it
+/// together with a new entry in `MIGRATIONS` whenever stored documents need to change.
+pub const SCHEMA_VERSION: u32 = 3;
+
+struct Migration {
+    /// The version the schema is at once this migration has run.
+    version: u32,
+    description: &'static str,
+    run: for<'a> fn(&'a Db) -> BoxFuture<'a, DbResult<()>>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "rewrite messages stored before the schema was versioned",
+        run: rewrite_unversioned_messages,
+    },
+    Migration {
+        version: 2,
+        description: "add a numeric timestamp to messages",
+        run: backfill_timestamps,
+    },
+    Migration {
+        version: 3,
+        description: "normalize messages as set up in NORMALIZE",
+        run: normalize_messages,
+    },
+];
+
+impl Db {
+    /// Reads the stored schema version. A backend without one is either empty, in which case
+    /// it is stamped with `SCHEMA_VERSION`, or holds documents from before versioning (0).
+    pub async fn schema_version(&self) -> DbResult<u32> {
+        if let Some(v) = self.get_schema_version().await? {
+            return Ok(v);
+        }
+        if self.get_messages(None, 0, 1).await?.is_empty() {
+            self.set_schema_version(SCHEMA_VERSION).await?;
+            Ok(SCHEMA_VERSION)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Fails unless the stored documents are exactly at `SCHEMA_VERSION`.
+    pub async fn check_schema_version(&self) -> DbResult<()> {
+        match self.schema_version().await? {
+            SCHEMA_VERSION => Ok(()),
+            v if v > SCHEMA_VERSION => Err(DbError::UnsupportedSchema(v)),
+            v => Err(DbError::OutdatedSchema(v)),
+        }
+    }
+
+    /// Runs every migration newer than the stored schema version, recording the version after
+    /// each of them so an interrupted run picks up where it stopped.
+    pub async fn migrate(&self) -> DbResult<()> {
+        let current = self.schema_version().await?;
+        if current > SCHEMA_VERSION {
+            return Err(DbError::UnsupportedSchema(current));
+        }
+        for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+            log::info!(
+                "Migrating to schema version {}: {}.",
+                m.version,
+                m.description
+            );
+            (m.run)(self).await?;
+            self.set_schema_version(m.version).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the normalized text and variants of every stored message again, as set up in
+    /// `NORMALIZE`.
+    pub async fn normalize_messages(&self) -> DbResult<()> {
+        self.rewrite_messages(|mut m| {
+            m.normalized = normalize(&m.text);
+            m.variants = variants(&m.text);
+            m
+        })
+        .await
+    }
+
+    /// Writes back what `f` makes of every stored message, a page at a time. Updated
+    /// messages keep their place in `get_messages`, so every one of them is seen once.
+    pub async fn rewrite_messages(&self, f: impl Fn(Message) -> Message) -> DbResult<()> {
+        let mut done = 0;
+        loop {
+            let page = self.get_messages(None, done, GET_LIMIT).await?;
+            let len = page.len();
+            self.insert_and_wait(&page.into_iter().map(&f).collect::<Vec<_>>())
+                .await?;
+            done += len;
+            if len < GET_LIMIT {
+                break;
+            }
+            log::info!("Rewrote {done} messages.");
+        }
+        log::info!("Rewrote {done} messages.");
+        Ok(())
+    }
+}
+
+/// Round-trips every message through the current `Message` type, so fields added since they
+/// were stored are written out.
+fn rewrite_unversioned_messages(db: &Db) -> BoxFuture<'_, DbResult<()>> {
+    Box::pin(db.rewrite_messages(|m| m))
+}
+
+fn normalize_messages(db: &Db) -> BoxFuture<'_, DbResult<()>> {
+    Box::pin(db.normalize_messages())
+}
+
+fn backfill_timestamps(db: &Db) -> BoxFuture<'_, DbResult<()>> {
+    Box::pin(db.rewrite_messages(|mut m| {
+        m.timestamp = m.date.timestamp();
+        m
+    }))
+}
+
+#[cfg(test)]
+mod migration_test {
+    use super::*;
+    use crate::db::embedded::embedded_test::*;
+
+    #[tokio::test]
+    async fn empty_test() {
+        let db = Db::from_backend(Memory::default());
+        db.check_schema_version().await.unwrap();
+        assert_eq!(db.get_schema_version().await.unwrap(), Some(SCHEMA_VERSION));
+    }
+
+    #[tokio::test]
+    async fn unversioned_test() {
+        let db = Db::from_backend(Memory::default());
+        db.insert(&[message(1, "旧的内容", 1)]).await.unwrap();
+
+        assert!(matches!(
+            db.check_schema_version().await,
+            Err(DbError::OutdatedSchema(0))
+        ));
+        db.migrate().await.unwrap();
+        db.check_schema_version().await.unwrap();
+        assert_eq!(db.get_messages(None, 0, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn normalize_test() {
+        let db = Db::from_backend(Memory::default());
+        db.set_schema_version(2).await.unwrap();
+        db.insert(&[Message {
+            normalized: Some("过时的内容".to_string()),
+            ..message(1, "内容", 1)
+        }])
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            db.check_schema_version().await,
+            Err(DbError::OutdatedSchema(2))
+        ));
+        db.migrate().await.unwrap();
+        db.check_schema_version().await.unwrap();
+        let m = db.get_message("-1001_1").await.unwrap().unwrap();
+        assert_eq!(m.normalized, normalize(&m.text));
+    }
+
+    #[tokio::test]
+    async fn unsupported_test() {
+        let db = Db::from_backend(Memory::default());
+        db.set_schema_version(SCHEMA_VERSION + 1).await.unwrap();
+
+        assert!(matches!(
+            db.check_schema_version().await,
+            Err(DbError::UnsupportedSchema(_))
+        ));
+        assert!(db.migrate().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrite_test() {
+        let db = Db::from_backend(Sqlite::in_memory());
+        let messages = (1..=250)
+            .map(|i| message(i, "内容", i.into()))
+            .collect::<Vec<_>>();
+        db.insert(&messages).await.unwrap();
+
+        db.rewrite_messages(|m| Message {
+            text: format!("{}!", m.text),
+            ..m
+        })
+        .await
+        .unwrap();
+
+        let messages = db.get_messages(None, 0, 1000).await.unwrap();
+        assert_eq!(messages.len(), 250);
+        assert!(messages.iter().all(|m| m.text == "内容!"));
+    }
+}
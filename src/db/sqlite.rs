@@ -0,0 +1,749 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+use teloxide::types::ChatId;
+
+use super::{embedded::*, *};
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS messages (
+    key TEXT PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    date INTEGER NOT NULL,
+    doc TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_chat_id ON messages (chat_id, id);
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+    tokens,
+    content = '',
+    contentless_delete = 1,
+    tokenize = 'ascii'
+);
+CREATE TABLE IF NOT EXISTS chats (
+    id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS senders (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    username TEXT
+);
+CREATE TABLE IF NOT EXISTS topics (
+    chat_id INTEGER NOT NULL,
+    thread_id INTEGER NOT NULL,
+    name TEXT,
+    disabled INTEGER NOT NULL,
+    PRIMARY KEY (chat_id, thread_id)
+);
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+
+/// Embedded backend on a single SQLite file. Messages are matched with FTS5 against the
+/// same CJK bigrams the Tantivy backend uses, which keeps memory usage low.
+pub struct Sqlite(Arc<Mutex<Connection>>);
+
+impl Sqlite {
+    pub fn from_env() -> DbResult<Self> {
+        Ok(Self::open(
+            std::env::var("SQLITE_PATH").unwrap_or("search.db".to_string()),
+        )?)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Ok(Sqlite(Arc::new(Mutex::new(Connection::open(path)?))))
+    }
+
+    pub fn in_memory() -> Self {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        Sqlite(Arc::new(Mutex::new(conn)))
+    }
+
+    /// Runs `f` on the connection in a blocking task, so that queries do not hold up the
+    /// async runtime.
+    async fn run<T, F>(&self, f: F) -> DbResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> DbResult<T> + Send + 'static,
+    {
+        let conn = self.0.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+fn create_tables(conn: &Connection) -> DbResult<()> {
+    conn.execute_batch(SCHEMA)?;
+    // Databases created before usernames were stored lack the column.
+    let has_username = conn
+        .prepare("SELECT 1 FROM pragma_table_info('senders') WHERE name = 'username'")?
+        .exists([])?;
+    if !has_username {
+        conn.execute("ALTER TABLE senders ADD COLUMN username TEXT", [])?;
+    }
+    Ok(())
+}
+
+/// Builds the query for the messages matching `query` and the parts of `filter` SQL can
+/// answer, in `filter.sort` order. Its last two parameters are left for the limit and offset.
+fn search_statement(query: &Expr, filter: &Filter<'_>) -> DbResult<(String, Vec<Value>)> {
+    let chats = filter.chats.iter().map(|c| c.id.0).collect::<Vec<_>>();
+    let mut params = vec![Value::Text(serde_json::to_string(&chats)?)];
+    let mut conditions = vec!["m.chat_id IN (SELECT value FROM json_each(?))".to_string()];
+
+    let expr = translate(query);
+    let (from, relevance) = match &expr {
+        Some(expr) => {
+            conditions.push("messages_fts MATCH ?".to_string());
+            params.push(Value::Text(expr.clone()));
+            (
+                "messages_fts JOIN messages m ON m.rowid = messages_fts.rowid",
+                "bm25(messages_fts), m.date DESC",
+            )
+        }
+        None => ("messages m", "m.date DESC"),
+    };
+
+    if let Some(t) = filter.since {
+        conditions.push("m.date >= ?".to_string());
+        params.push(Value::Integer(t.timestamp()));
+    }
+    if let Some(t) = filter.until {
+        conditions.push("m.date < ?".to_string());
+        params.push(Value::Integer(t.timestamp()));
+    }
+    if let Some(s) = filter.sender {
+        conditions.push("json_extract(m.doc, '$.sender') = ?".to_string());
+        params.push(Value::Integer(s.0));
+    }
+    match filter.urls {
+        EnableOption::All => {
+            conditions.push("json_extract(m.doc, '$.web_page') IS NOT NULL".to_string())
+        }
+        EnableOption::Enable => {}
+        EnableOption::Disable => {
+            conditions.push("json_extract(m.doc, '$.web_page') IS NULL".to_string())
+        }
+    }
+    if !filter.history {
+        conditions.push("json_extract(m.doc, '$.revised') IS NULL".to_string());
+    }
+
+    let order = match filter.sort {
+        SortOrder::Relevance => relevance,
+        SortOrder::Newest => "m.date DESC",
+        SortOrder::Oldest => "m.date ASC",
+    };
+    Ok((
+        format!(
+            "SELECT m.doc FROM {from} WHERE {} ORDER BY {order} LIMIT ? OFFSET ?",
+            conditions.join(" AND ")
+        ),
+        params,
+    ))
+}
+
+fn insert_messages(conn: &mut Connection, docs: &[Message]) -> DbResult<()> {
+    let tx = conn.transaction()?;
+    for m in docs {
+        tx.execute(
+            "DELETE FROM messages_fts WHERE rowid IN (SELECT rowid FROM messages WHERE key = ?1)",
+            params![m.key],
+        )?;
+        tx.execute("DELETE FROM messages WHERE key = ?1", params![m.key])?;
+        tx.execute(
+            "INSERT INTO messages (key, chat_id, id, date, doc) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                m.key,
+                m.chat_id.0,
+                m.id,
+                m.date.timestamp(),
+                serde_json::to_string(m)?
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO messages_fts (rowid, tokens) VALUES (last_insert_rowid(), ?1)",
+            params![message_tokens(m).join(" ")],
+        )?;
+    }
+    Ok(tx.commit()?)
+}
+
+fn insert_chats(conn: &mut Connection, docs: &[Chat]) -> DbResult<()> {
+    let tx = conn.transaction()?;
+    for c in docs {
+        tx.execute(
+            "INSERT OR REPLACE INTO chats (id) VALUES (?1)",
+            params![c.id.0],
+        )?;
+    }
+    Ok(tx.commit()?)
+}
+
+fn insert_topics(conn: &mut Connection, docs: &[Topic]) -> DbResult<()> {
+    let tx = conn.transaction()?;
+    for t in docs {
+        tx.execute(
+            "INSERT OR REPLACE INTO topics (chat_id, thread_id, name, disabled)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![t.chat_id.0, t.thread_id, t.name, t.disabled],
+        )?;
+    }
+    Ok(tx.commit()?)
+}
+
+fn insert_senders(conn: &mut Connection, docs: &[Sender]) -> DbResult<()> {
+    let tx = conn.transaction()?;
+    for s in docs {
+        tx.execute(
+            "INSERT OR REPLACE INTO senders (id, name, username) VALUES (?1, ?2, ?3)",
+            params![s.id.0, s.name, s.username],
+        )?;
+    }
+    Ok(tx.commit()?)
+}
+
+fn parse_docs(docs: Vec<String>) -> DbResult<Vec<Message>> {
+    docs.iter().map(|d| Ok(serde_json::from_str(d)?)).collect()
+}
+
+/// Translates `query` into an FTS5 expression matching at least every message it matches,
+/// `None` meaning all of them. FTS5 has no standalone `NOT`, phrases only require their
+/// tokens and only words of `exact_tokens` are excluded, `paginate` checks the rest.
+fn translate(query: &Expr) -> Option<String> {
+    match query {
+        Expr::Term(t) | Expr::Phrase(t) => {
+            let alternatives = match query {
+                Expr::Term(_) => term_alternatives(t),
+                _ => vec![query_tokens(t)],
+            };
+            alternatives
+                .iter()
+                .map(|tokens| {
+                    (!tokens.is_empty()).then(|| {
+                        format!(
+                            "({})",
+                            tokens
+                                .iter()
+                                .map(|t| format!("\"{t}\""))
+                                .collect::<Vec<_>>()
+                                .join(" AND ")
+                        )
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|v| format!("({})", v.join(" OR ")))
+        }
+        Expr::Not(_) => None,
+        Expr::And(v) => {
+            let must = v.iter().filter_map(translate).collect::<Vec<_>>();
+            let must_not = v.iter().filter_map(|e| match e {
+                Expr::Not(e) => exact_tokens(e).map(|tokens| {
+                    format!(
+                        "({})",
+                        tokens
+                            .iter()
+                            .map(|t| format!("\"{t}\""))
+                            .collect::<Vec<_>>()
+                            .join(" OR ")
+                    )
+                }),
+                _ => None,
+            });
+            (!must.is_empty()).then(|| {
+                std::iter::once(format!("({})", must.join(" AND ")))
+                    .chain(must_not)
+                    .collect::<Vec<_>>()
+                    .join(" NOT ")
+            })
+        }
+        Expr::Or(v) => v
+            .iter()
+            .map(translate)
+            .collect::<Option<Vec<_>>>()
+            .map(|v| format!("({})", v.join(" OR "))),
+    }
+}
+
+#[async_trait]
+impl SearchBackend for Sqlite {
+    async fn init(&self) -> DbResult<()> {
+        self.run(|conn| create_tables(conn)).await
+    }
+
+    async fn search_message_with_filter(
+        &self,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: Option<usize>,
+    ) -> DbResult<Vec<SearchHit>> {
+        let (sql, params) = search_statement(query, filter)?;
+        paginate(
+            |offset, limit| {
+                let (sql, mut params) = (sql.clone(), params.clone());
+                params.extend([Value::Integer(limit as i64), Value::Integer(offset as i64)]);
+                self.run(move |conn| {
+                    let docs = conn
+                        .prepare_cached(&sql)?
+                        .query_map(params_from_iter(params), |r| r.get::<_, String>(0))?
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+                    parse_docs(docs)
+                })
+            },
+            query,
+            filter,
+            offset,
+        )
+        .await
+    }
+
+    async fn delete_chat_with_id(&self, id: ChatId) -> DbResult<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM chats WHERE id = ?1", params![id.0])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn filter_chat_with_id(&self, id: ChatId) -> DbResult<Option<Chat>> {
+        self.run(move |conn| {
+            Ok(conn
+                .query_row("SELECT id FROM chats WHERE id = ?1", params![id.0], |r| {
+                    Ok(Chat::from(ChatId(r.get(0)?)))
+                })
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn get_all_chats(&self) -> DbResult<Vec<ChatId>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT id FROM chats")?;
+            let chats = stmt
+                .query_map([], |r| Ok(ChatId(r.get(0)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(chats)
+        })
+        .await
+    }
+
+    async fn get_sender_name(&self, id: ChatId) -> DbResult<Option<String>> {
+        self.run(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT name FROM senders WHERE id = ?1",
+                    params![id.0],
+                    |r| r.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn search_senders(&self, name: &str) -> DbResult<Vec<Sender>> {
+        let senders = self
+            .run(|conn| {
+                let mut stmt = conn.prepare("SELECT id, name, username FROM senders")?;
+                let senders = stmt
+                    .query_map([], |r| {
+                        Ok(Sender {
+                            id: ChatId(r.get(0)?),
+                            name: r.get(1)?,
+                            username: r.get(2)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(senders)
+            })
+            .await?;
+        Ok(rank_senders(senders, name))
+    }
+
+    async fn get_topics(&self, chat: ChatId) -> DbResult<Vec<Topic>> {
+        self.run(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT thread_id, name, disabled FROM topics WHERE chat_id = ?1")?;
+            let topics = stmt
+                .query_map(params![chat.0], |r| {
+                    Ok(Topic {
+                        disabled: r.get(2)?,
+                        ..Topic::new(chat, r.get(0)?, r.get(1)?)
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(topics)
+        })
+        .await
+    }
+
+    async fn get_messages(
+        &self,
+        chat: Option<ChatId>,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<Message>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT doc FROM messages WHERE ?1 IS NULL OR chat_id = ?1
+                ORDER BY key LIMIT ?2 OFFSET ?3",
+            )?;
+            let docs = stmt
+                .query_map(
+                    params![chat.map(|c| c.0), limit as i64, offset as i64],
+                    |r| r.get::<_, String>(0),
+                )?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            parse_docs(docs)
+        })
+        .await
+    }
+
+    async fn get_message(&self, key: &str) -> DbResult<Option<Message>> {
+        let key = key.to_string();
+        self.run(move |conn| {
+            let doc = conn
+                .query_row(
+                    "SELECT doc FROM messages WHERE key = ?1",
+                    params![key],
+                    |r| r.get::<_, String>(0),
+                )
+                .optional()?;
+            Ok(doc.map(|d| serde_json::from_str(&d)).transpose()?)
+        })
+        .await
+    }
+
+    async fn get_surrounding_messages(
+        &self,
+        chat: ChatId,
+        id: i32,
+        n: usize,
+    ) -> DbResult<Vec<Message>> {
+        self.run(move |conn| {
+            // Both halves walk the (chat_id, id) index from `id` outwards.
+            let mut stmt = conn.prepare(
+                "SELECT doc FROM (
+                    SELECT doc, id FROM messages
+                    WHERE chat_id = ?1 AND id < ?2
+                        AND json_extract(doc, '$.web_page') IS NULL
+                        AND json_extract(doc, '$.revised') IS NULL
+                    ORDER BY id DESC LIMIT ?3
+                )
+                UNION ALL
+                SELECT doc FROM (
+                    SELECT doc, id FROM messages
+                    WHERE chat_id = ?1 AND id >= ?2
+                        AND json_extract(doc, '$.web_page') IS NULL
+                        AND json_extract(doc, '$.revised') IS NULL
+                    ORDER BY id ASC LIMIT ?3 + 1
+                )",
+            )?;
+            let docs = stmt
+                .query_map(params![chat.0, id, n as i64], |r| r.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut messages = parse_docs(docs)?;
+            messages.sort_by_key(|m| m.id);
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn delete_messages(&self, keys: &[String]) -> DbResult<()> {
+        let keys = keys.to_vec();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            for k in keys {
+                tx.execute(
+                    "DELETE FROM messages_fts WHERE rowid IN (SELECT rowid FROM messages WHERE key = ?1)",
+                    params![k],
+                )?;
+                tx.execute("DELETE FROM messages WHERE key = ?1", params![k])?;
+            }
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    async fn get_schema_version(&self) -> DbResult<Option<u32>> {
+        self.run(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT value FROM meta WHERE key = 'schema_version'",
+                    [],
+                    |r| r.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn set_schema_version(&self, version: u32) -> DbResult<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?1)",
+                params![version],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_documents(&self, docs: Documents<'_>) -> DbResult<()> {
+        match docs {
+            Documents::Messages(d) => {
+                let d = d.to_vec();
+                self.run(move |conn| insert_messages(conn, &d)).await
+            }
+            Documents::Chats(d) => {
+                let d = d.to_vec();
+                self.run(move |conn| insert_chats(conn, &d)).await
+            }
+            Documents::Senders(d) => {
+                let d = d.to_vec();
+                self.run(move |conn| insert_senders(conn, &d)).await
+            }
+            Documents::Topics(d) => {
+                let d = d.to_vec();
+                self.run(move |conn| insert_topics(conn, &d)).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod sqlite_test {
+    use super::{embedded_test::*, *};
+
+    #[tokio::test]
+    async fn search_cjk_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "经济发展很快", 1),
+            message(2, "今天天气不错", 2),
+            message(3, "发展是硬道理", 3),
+        ]))
+        .await
+        .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("发展"), &filter(&[-1001]), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            hits.iter().map(|h| h.result.id).collect::<Vec<_>>(),
+            vec![3, 1]
+        );
+        assert!(db
+            .search_message_with_filter(&query("发展"), &filter(&[-1002]), None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn crop_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Messages(&[message(
+            1,
+            "one two three four five six seven eight nine ten",
+            1,
+        )]))
+        .await
+        .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("seven"), &filter(&[-1001]), None)
+            .await
+            .unwrap();
+        assert_eq!(hits[0].formatted_text, "…five six seven eight nine ten");
+    }
+
+    #[tokio::test]
+    async fn operators_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "经济发展很快", 1),
+            message(2, "今天天气不错", 2),
+            message(3, "发展是硬道理", 3),
+            message(4, "world hello", 4),
+        ]))
+        .await
+        .unwrap();
+
+        let ids = |hits: Vec<SearchHit>| hits.iter().map(|h| h.result.id).collect::<Vec<_>>();
+        for (q, expected) in [
+            (r#"hello -"hello world""#, vec![4]),
+            (r#"hello -"world hello""#, vec![]),
+            ("发展 -经济", vec![3]),
+            ("天气 OR 道理", vec![3, 2]),
+            (r#""展是""#, vec![3]),
+            ("-(发展 OR 天气)", vec![4]),
+        ] {
+            let hits = db
+                .search_message_with_filter(&query(q), &filter(&[-1001]), None)
+                .await
+                .unwrap();
+            assert_eq!(ids(hits), expected, "{q}");
+        }
+    }
+
+    #[tokio::test]
+    async fn upsert_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Messages(&[message(1, "旧的内容", 1)]))
+            .await
+            .unwrap();
+        db.insert_documents(Documents::Messages(&[message(1, "新的内容", 1)]))
+            .await
+            .unwrap();
+
+        assert!(db
+            .search_message_with_filter(&query("旧的"), &filter(&[-1001]), None)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.search_message_with_filter(&query("内容"), &filter(&[-1001]), None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_sort_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "发展一", 10),
+            Message {
+                sender: Some(ChatId(2)),
+                ..message(2, "发展二", 20)
+            },
+            message(3, "发展三", 30),
+            Message {
+                revised: Some(40),
+                ..message(4, "发展四", 40)
+            },
+        ]))
+        .await
+        .unwrap();
+
+        let ids = |hits: Vec<SearchHit>| hits.iter().map(|h| h.result.id).collect::<Vec<_>>();
+        for (q, filter, expected) in [
+            (
+                "发展",
+                Filter {
+                    since: chrono::DateTime::from_timestamp(20, 0),
+                    sort: SortOrder::Oldest,
+                    ..filter(&[-1001])
+                },
+                vec![2, 3],
+            ),
+            (
+                "",
+                Filter {
+                    sender: Some(ChatId(2)),
+                    ..filter(&[-1001])
+                },
+                vec![2],
+            ),
+            (
+                "",
+                Filter {
+                    history: true,
+                    sort: SortOrder::Newest,
+                    ..filter(&[-1001])
+                },
+                vec![4, 3, 2, 1],
+            ),
+        ] {
+            let hits = db
+                .search_message_with_filter(&query(q), &filter, None)
+                .await
+                .unwrap();
+            assert_eq!(ids(hits), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn pagination_test() {
+        let db = Sqlite::in_memory();
+        let messages = (1..=250)
+            .map(|i| message(i, if i % 2 == 0 { "偶数" } else { "奇数" }, i.into()))
+            .collect::<Vec<_>>();
+        db.insert_documents(Documents::Messages(&messages))
+            .await
+            .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("偶数"), &filter(&[-1001]), Some(60))
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), INLINE_REPLY_LIMIT);
+        assert_eq!(hits[0].result.id, 130);
+    }
+
+    #[tokio::test]
+    async fn surrounding_test() {
+        let db = Sqlite::in_memory();
+        let mut messages = (1..=10)
+            .map(|i| message(i, "消息", i.into()))
+            .collect::<Vec<_>>();
+        messages.push(Message {
+            key: "-1001_5_r50".to_string(),
+            revised: Some(50),
+            ..message(5, "旧消息", 5)
+        });
+        messages.push(Message {
+            chat_id: ChatId(-1002),
+            key: "-1002_4".to_string(),
+            ..message(4, "消息", 4)
+        });
+        db.insert_documents(Documents::Messages(&messages))
+            .await
+            .unwrap();
+
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        for (id, n, expected) in [
+            (5, 2, vec![3, 4, 5, 6, 7]),
+            (1, 2, vec![1, 2, 3]),
+            (10, 1, vec![9, 10]),
+            (5, 0, vec![5]),
+        ] {
+            let surrounding = db
+                .get_surrounding_messages(ChatId(-1001), id, n)
+                .await
+                .unwrap();
+            assert_eq!(ids(surrounding), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn senders_test() {
+        let db = Sqlite::in_memory();
+        db.insert_documents(Documents::Senders(&[Sender {
+            id: ChatId(1),
+            name: "Foo".to_string(),
+            username: Some("foo_bar".to_string()),
+        }]))
+        .await
+        .unwrap();
+
+        assert_eq!(db.get_sender_name(ChatId(1)).await.unwrap().unwrap(), "Foo");
+        assert!(db.get_sender_name(ChatId(2)).await.unwrap().is_none());
+        assert_eq!(
+            db.search_senders("@Foo_Bar").await.unwrap()[0].id,
+            ChatId(1)
+        );
+    }
+}
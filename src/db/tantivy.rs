@@ -0,0 +1,1035 @@
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ::tantivy::{
+    collector::TopDocs,
+    directory::{MmapDirectory, RamDirectory},
+    query::{
+        AllQuery, BooleanQuery, FuzzyTermQuery, Occur, Query, RangeQuery, RegexQuery, TermQuery,
+        TermSetQuery,
+    },
+    schema::{
+        Field, IndexRecordOption, Schema, SchemaBuilder, TextFieldIndexing, TextOptions, Value,
+        FAST, INDEXED, STORED, STRING,
+    },
+    tokenizer::{Token, TokenStream, Tokenizer},
+    Directory, DocAddress, DocId, Index, IndexReader, IndexWriter, Order, ReloadPolicy, Score,
+    Searcher, SegmentReader, TantivyDocument, Term,
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use teloxide::types::ChatId;
+
+use super::{embedded::*, *};
+
+const TOKENIZER: &str = "cjk";
+const META_INDEX: &str = "meta";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const WRITER_MEMORY_BUDGET: usize = 15_000_000;
+/// Values of the `flags` field, marking documents that are not messages themselves.
+const WEB_PAGE_FLAG: &str = "web_page";
+const REVISION_FLAG: &str = "revision";
+
+/// Most typos `FuzzyTermQuery` tolerates.
+const MAX_FUZZY_DISTANCE: usize = 2;
+
+/// Embedded backend keeping every index in a local directory, for deployments without a
+/// Meilisearch server.
+pub struct Tantivy(Arc<Stores>);
+
+struct Stores {
+    messages: Store,
+    chats: Store,
+    senders: Store,
+    topics: Store,
+    meta: Store,
+    message_fields: MessageFields,
+    /// Lowercased names, usernames and words of names of senders.
+    sender_names: Field,
+    topic_chat_id: Field,
+}
+
+struct Store {
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    key: Field,
+    doc: Field,
+}
+
+/// Fields of the messages index that queries and filters run against.
+#[derive(Clone, Copy)]
+struct MessageFields {
+    text: Field,
+    chat_id: Field,
+    id: Field,
+    date: Field,
+    sender: Field,
+    flags: Field,
+}
+
+#[derive(Clone)]
+struct CjkTokenizer;
+
+struct CjkTokenStream {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Tantivy {
+    pub fn from_env() -> DbResult<Self> {
+        Ok(Self::open(
+            std::env::var("TANTIVY_PATH").unwrap_or("tantivy_data".to_string()),
+        )?)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> ::tantivy::Result<Self> {
+        Self::with_directories(|name| {
+            let path = path.as_ref().join(name);
+            std::fs::create_dir_all(&path)?;
+            Ok(Box::new(MmapDirectory::open(path)?))
+        })
+    }
+
+    pub fn in_ram() -> Self {
+        Self::with_directories(|_| Ok(Box::new(RamDirectory::create()))).unwrap()
+    }
+
+    fn with_directories(
+        dir: impl Fn(&str) -> ::tantivy::Result<Box<dyn Directory>>,
+    ) -> ::tantivy::Result<Self> {
+        let (messages, message_fields) = Store::open(dir(Message::INDEX)?, message_schema)?;
+        let (chats, ()) = Store::open(dir(Chat::INDEX)?, |_| ())?;
+        let (senders, sender_names) =
+            Store::open(dir(Sender::INDEX)?, |b| b.add_text_field("names", STRING))?;
+        let (topics, topic_chat_id) =
+            Store::open(dir(Topic::INDEX)?, |b| b.add_i64_field("chat_id", INDEXED))?;
+        let (meta, ()) = Store::open(dir(META_INDEX)?, |_| ())?;
+        Ok(Tantivy(Arc::new(Stores {
+            messages,
+            chats,
+            senders,
+            topics,
+            meta,
+            message_fields,
+            sender_names,
+            topic_chat_id,
+        })))
+    }
+
+    /// Runs `f` on the indexes in a blocking task, so that commits and searches do not hold
+    /// up the async runtime.
+    async fn run<T, F>(&self, f: F) -> DbResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Stores) -> DbResult<T> + Send + 'static,
+    {
+        let stores = self.0.clone();
+        tokio::task::spawn_blocking(move || f(&stores))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+#[async_trait]
+impl SearchBackend for Tantivy {
+    async fn init(&self) -> DbResult<()> {
+        Ok(())
+    }
+
+    async fn search_message_with_filter(
+        &self,
+        query: &Expr,
+        filter: &Filter<'_>,
+        offset: Option<usize>,
+    ) -> DbResult<Vec<SearchHit>> {
+        let fields = self.0.message_fields;
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(
+            Occur::Must,
+            Box::new(TermSetQuery::new(
+                filter
+                    .chats
+                    .iter()
+                    .map(|c| Term::from_field_i64(fields.chat_id, c.id.0)),
+            )),
+        )];
+        clauses.extend(translate(query, fields.text).map(|q| (Occur::Must, q)));
+        clauses.extend(filter_clauses(filter, fields));
+        let query_all = Arc::new(BooleanQuery::new(clauses));
+        let sort = filter.sort;
+
+        paginate(
+            |offset, limit| {
+                let query_all = query_all.clone();
+                self.run(move |s| s.messages.search(query_all.as_ref(), sort, offset, limit))
+            },
+            query,
+            filter,
+            offset,
+        )
+        .await
+    }
+
+    async fn delete_chat_with_id(&self, id: ChatId) -> DbResult<()> {
+        self.run(move |s| s.chats.delete(&[id.to_string()])).await
+    }
+
+    async fn filter_chat_with_id(&self, id: ChatId) -> DbResult<Option<Chat>> {
+        self.run(move |s| s.chats.get(&id.to_string())).await
+    }
+
+    async fn get_all_chats(&self) -> DbResult<Vec<ChatId>> {
+        self.run(|s| Ok(s.chats.all::<Chat>()?.into_iter().map(|c| c.id).collect()))
+            .await
+    }
+
+    async fn get_sender_name(&self, id: ChatId) -> DbResult<Option<String>> {
+        self.run(move |s| Ok(s.senders.get(&id.to_string())?.map(|s: Sender| s.name)))
+            .await
+    }
+
+    async fn search_senders(&self, name: &str) -> DbResult<Vec<Sender>> {
+        let name = name.to_string();
+        self.run(move |s| {
+            let candidates = s.senders.matching(&sender_query(&name, s.sender_names)?)?;
+            Ok(rank_senders(candidates, &name))
+        })
+        .await
+    }
+
+    async fn get_topics(&self, chat: ChatId) -> DbResult<Vec<Topic>> {
+        self.run(move |s| {
+            s.topics.matching(&TermQuery::new(
+                Term::from_field_i64(s.topic_chat_id, chat.0),
+                IndexRecordOption::Basic,
+            ))
+        })
+        .await
+    }
+
+    async fn get_messages(
+        &self,
+        chat: Option<ChatId>,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<Message>> {
+        self.run(move |s| {
+            let query: Box<dyn Query> = match chat {
+                Some(c) => Box::new(TermQuery::new(
+                    Term::from_field_i64(s.message_fields.chat_id, c.0),
+                    IndexRecordOption::Basic,
+                )),
+                None => Box::new(AllQuery),
+            };
+            s.messages.by_key(query.as_ref(), offset, limit)
+        })
+        .await
+    }
+
+    async fn get_message(&self, key: &str) -> DbResult<Option<Message>> {
+        let key = key.to_string();
+        self.run(move |s| s.messages.get(&key)).await
+    }
+
+    async fn get_surrounding_messages(
+        &self,
+        chat: ChatId,
+        id: i32,
+        n: usize,
+    ) -> DbResult<Vec<Message>> {
+        self.run(move |s| {
+            let fields = s.message_fields;
+            let term_query =
+                |term| Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>;
+            let around = |lower, upper| {
+                BooleanQuery::new(vec![
+                    (
+                        Occur::Must,
+                        term_query(Term::from_field_i64(fields.chat_id, chat.0)),
+                    ),
+                    (Occur::Must, Box::new(RangeQuery::new(lower, upper))),
+                    (
+                        Occur::MustNot,
+                        term_query(Term::from_field_text(fields.flags, WEB_PAGE_FLAG)),
+                    ),
+                    (
+                        Occur::MustNot,
+                        term_query(Term::from_field_text(fields.flags, REVISION_FLAG)),
+                    ),
+                ])
+            };
+            let id = Term::from_field_i64(fields.id, id.into());
+
+            let mut messages = match n {
+                0 => vec![],
+                _ => s.messages.by_id(
+                    &around(Bound::Unbounded, Bound::Excluded(id.clone())),
+                    Order::Desc,
+                    n,
+                )?,
+            };
+            messages.reverse();
+            messages.extend(s.messages.by_id::<Message>(
+                &around(Bound::Included(id), Bound::Unbounded),
+                Order::Asc,
+                n + 1,
+            )?);
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn delete_messages(&self, keys: &[String]) -> DbResult<()> {
+        let keys = keys.to_vec();
+        self.run(move |s| s.messages.delete(&keys)).await
+    }
+
+    async fn get_schema_version(&self) -> DbResult<Option<u32>> {
+        self.run(|s| s.meta.get(SCHEMA_VERSION_KEY)).await
+    }
+
+    async fn set_schema_version(&self, version: u32) -> DbResult<()> {
+        self.run(move |s| {
+            s.meta.upsert(std::iter::once(Ok((
+                SCHEMA_VERSION_KEY.to_string(),
+                s.meta.document(SCHEMA_VERSION_KEY, &version)?,
+            ))))
+        })
+        .await
+    }
+
+    async fn insert_documents(&self, docs: Documents<'_>) -> DbResult<()> {
+        match docs {
+            Documents::Messages(d) => {
+                let d = d.to_vec();
+                self.run(move |s| {
+                    let fields = s.message_fields;
+                    s.messages.upsert(d.iter().map(|m| {
+                        let mut doc = s.messages.document(&m.key, m)?;
+                        doc.add_text(fields.text, m.search_text());
+                        if let Some(v) = &m.variants {
+                            doc.add_text(fields.text, v);
+                        }
+                        doc.add_i64(fields.chat_id, m.chat_id.0);
+                        doc.add_i64(fields.id, m.id.into());
+                        doc.add_i64(fields.date, m.date.timestamp());
+                        if let Some(s) = m.sender {
+                            doc.add_i64(fields.sender, s.0);
+                        }
+                        if m.web_page.is_some() {
+                            doc.add_text(fields.flags, WEB_PAGE_FLAG);
+                        }
+                        if m.revised.is_some() {
+                            doc.add_text(fields.flags, REVISION_FLAG);
+                        }
+                        Ok((m.key.clone(), doc))
+                    }))
+                })
+                .await
+            }
+            Documents::Chats(d) => {
+                let d = d.to_vec();
+                self.run(move |s| {
+                    s.chats.upsert(d.iter().map(|c| {
+                        let key = c.id.to_string();
+                        Ok((key.clone(), s.chats.document(&key, c)?))
+                    }))
+                })
+                .await
+            }
+            Documents::Senders(d) => {
+                let d = d.to_vec();
+                self.run(move |s| {
+                    s.senders.upsert(d.iter().map(|sender| {
+                        let key = sender.id.to_string();
+                        let mut doc = s.senders.document(&key, sender)?;
+                        for name in sender_names(sender) {
+                            doc.add_text(s.sender_names, name);
+                        }
+                        Ok((key, doc))
+                    }))
+                })
+                .await
+            }
+            Documents::Topics(d) => {
+                let d = d.to_vec();
+                self.run(move |s| {
+                    s.topics.upsert(d.iter().map(|t| {
+                        let mut doc = s.topics.document(&t.key, t)?;
+                        doc.add_i64(s.topic_chat_id, t.chat_id.0);
+                        Ok((t.key.clone(), doc))
+                    }))
+                })
+                .await
+            }
+        }
+    }
+}
+
+/// What `search_senders` looks up a sender by, its name, username and the words of its name.
+fn sender_names(sender: &Sender) -> Vec<String> {
+    let name = sender.name.to_lowercase();
+    let mut names = name
+        .split_whitespace()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    names.push(name);
+    names.extend(sender.username.as_ref().map(|u| u.to_lowercase()));
+    names
+}
+
+/// Finds the senders `rank_senders` may keep for `name`, those with a name containing it and
+/// those with a word a few typos away from it.
+fn sender_query(name: &str, field: Field) -> DbResult<BooleanQuery> {
+    let name = name.trim_start_matches('@').to_lowercase();
+    let escaped = name
+        .chars()
+        .map(|c| match c {
+            '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$'
+            | '#' | '&' | '-' | '~' => format!("\\{c}"),
+            c => c.to_string(),
+        })
+        .collect::<String>();
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(
+        Occur::Should,
+        Box::new(RegexQuery::from_pattern(&format!(".*{escaped}.*"), field)?),
+    )];
+    let typos = (name.chars().count() / 4).min(MAX_FUZZY_DISTANCE);
+    if typos > 0 {
+        clauses.push((
+            Occur::Should,
+            Box::new(FuzzyTermQuery::new(
+                Term::from_field_text(field, &name),
+                typos as u8,
+                true,
+            )),
+        ));
+    }
+    Ok(BooleanQuery::new(clauses))
+}
+
+/// Clauses for the parts of `filter` the index can answer, the date range, the sender and
+/// which kinds of documents to include. `paginate` checks the rest.
+fn filter_clauses(filter: &Filter<'_>, fields: MessageFields) -> Vec<(Occur, Box<dyn Query>)> {
+    let term_query =
+        |term| Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>;
+    let flag = |f| term_query(Term::from_field_text(fields.flags, f));
+    let mut res = vec![];
+
+    if filter.since.is_some() || filter.until.is_some() {
+        let bound = |t: Option<DateTime<Utc>>, f: fn(Term) -> Bound<Term>| {
+            t.map_or(Bound::Unbounded, |t| {
+                f(Term::from_field_i64(fields.date, t.timestamp()))
+            })
+        };
+        res.push((
+            Occur::Must,
+            Box::new(RangeQuery::new(
+                bound(filter.since, Bound::Included),
+                bound(filter.until, Bound::Excluded),
+            )) as Box<dyn Query>,
+        ));
+    }
+    if let Some(s) = filter.sender {
+        res.push((
+            Occur::Must,
+            term_query(Term::from_field_i64(fields.sender, s.0)),
+        ));
+    }
+    match filter.urls {
+        EnableOption::All => res.push((Occur::Must, flag(WEB_PAGE_FLAG))),
+        EnableOption::Enable => {}
+        EnableOption::Disable => res.push((Occur::MustNot, flag(WEB_PAGE_FLAG))),
+    }
+    if !filter.history {
+        res.push((Occur::MustNot, flag(REVISION_FLAG)));
+    }
+    res
+}
+
+/// Translates `query` into one matching at least every message it matches, `None` meaning
+/// all of them. Phrases only require their tokens and only words of `exact_tokens` are
+/// excluded, `paginate` checks the rest.
+fn translate(query: &Expr, field: Field) -> Option<Box<dyn Query>> {
+    match query {
+        Expr::Term(t) | Expr::Phrase(t) => {
+            let alternatives = match query {
+                Expr::Term(_) => term_alternatives(t),
+                _ => vec![query_tokens(t)],
+            };
+            alternatives
+                .iter()
+                .map(|tokens| {
+                    (!tokens.is_empty()).then(|| {
+                        let all = tokens
+                            .iter()
+                            .map(|t| {
+                                (
+                                    Occur::Must,
+                                    Box::new(TermQuery::new(
+                                        Term::from_field_text(field, t),
+                                        IndexRecordOption::WithFreqs,
+                                    )) as Box<dyn Query>,
+                                )
+                            })
+                            .collect();
+                        (
+                            Occur::Should,
+                            Box::new(BooleanQuery::new(all)) as Box<dyn Query>,
+                        )
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|clauses| Box::new(BooleanQuery::new(clauses)) as Box<dyn Query>)
+        }
+        Expr::Not(e) => exact_tokens(e).map(|tokens| {
+            Box::new(BooleanQuery::new(vec![
+                (Occur::Must, Box::new(AllQuery) as Box<dyn Query>),
+                (
+                    Occur::MustNot,
+                    Box::new(TermSetQuery::new(
+                        tokens.iter().map(|t| Term::from_field_text(field, t)),
+                    )),
+                ),
+            ])) as Box<dyn Query>
+        }),
+        Expr::And(v) => {
+            let clauses = v
+                .iter()
+                .filter_map(|e| translate(e, field))
+                .map(|q| (Occur::Must, q))
+                .collect::<Vec<_>>();
+            (!clauses.is_empty()).then(|| Box::new(BooleanQuery::new(clauses)) as Box<dyn Query>)
+        }
+        Expr::Or(v) => v
+            .iter()
+            .map(|e| translate(e, field).map(|q| (Occur::Should, q)))
+            .collect::<Option<Vec<_>>>()
+            .map(|clauses| Box::new(BooleanQuery::new(clauses)) as Box<dyn Query>),
+    }
+}
+
+fn message_schema(builder: &mut SchemaBuilder) -> MessageFields {
+    MessageFields {
+        text: builder.add_text_field(
+            "text",
+            TextOptions::default().set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(TOKENIZER)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            ),
+        ),
+        chat_id: builder.add_i64_field("chat_id", INDEXED),
+        id: builder.add_i64_field("id", INDEXED | FAST),
+        date: builder.add_i64_field("date", INDEXED | FAST),
+        sender: builder.add_i64_field("sender", INDEXED),
+        flags: builder.add_text_field("flags", STRING),
+    }
+}
+
+impl Store {
+    /// Opens the index in `dir` with the key and document fields, and whatever `fields` adds
+    /// to look documents up by.
+    fn open<F>(
+        dir: Box<dyn Directory>,
+        fields: impl FnOnce(&mut SchemaBuilder) -> F,
+    ) -> ::tantivy::Result<(Self, F)> {
+        let mut builder = Schema::builder();
+        let key = builder.add_text_field("key", STRING | FAST);
+        let doc = builder.add_text_field("doc", STORED);
+        let fields = fields(&mut builder);
+
+        let index = Index::open_or_create(dir, builder.build())?;
+        index.tokenizers().register(TOKENIZER, CjkTokenizer);
+        let store = Store {
+            writer: Mutex::new(index.writer_with_num_threads(1, WRITER_MEMORY_BUDGET)?),
+            reader: index
+                .reader_builder()
+                .reload_policy(ReloadPolicy::Manual)
+                .try_into()?,
+            key,
+            doc,
+        };
+        Ok((store, fields))
+    }
+
+    fn document(&self, key: &str, d: &impl Serialize) -> DbResult<TantivyDocument> {
+        let mut doc = TantivyDocument::new();
+        doc.add_text(self.key, key);
+        doc.add_text(self.doc, serde_json::to_string(d)?);
+        Ok(doc)
+    }
+
+    fn upsert(
+        &self,
+        docs: impl Iterator<Item = DbResult<(String, TantivyDocument)>>,
+    ) -> DbResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for d in docs {
+            let (key, doc) = d?;
+            writer.delete_term(Term::from_field_text(self.key, &key));
+            writer.add_document(doc)?;
+        }
+        writer.commit()?;
+        Ok(self.reader.reload()?)
+    }
+
+    fn delete(&self, keys: &[String]) -> DbResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for key in keys {
+            writer.delete_term(Term::from_field_text(self.key, key));
+        }
+        writer.commit()?;
+        Ok(self.reader.reload()?)
+    }
+
+    fn get<T>(&self, key: &str) -> DbResult<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let query = TermQuery::new(
+            Term::from_field_text(self.key, key),
+            IndexRecordOption::Basic,
+        );
+        Ok(self.by_key(&query, 0, 1)?.pop())
+    }
+
+    /// Every document, for the small indexes of chats, senders and topics.
+    fn all<T>(&self) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        self.matching(&AllQuery)
+    }
+
+    /// Every document matching `query`, for the small indexes.
+    fn matching<T>(&self, query: &dyn Query) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let limit = self.reader.searcher().num_docs() as usize;
+        self.by_key(query, 0, limit.max(1))
+    }
+
+    /// The page of documents matching `query` at `offset`, in a stable order that documents
+    /// keep when they are updated.
+    fn by_key<T>(&self, query: &dyn Query, offset: usize, limit: usize) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let searcher = self.reader.searcher();
+        let collector = TopDocs::with_limit(limit)
+            .and_offset(offset)
+            .order_by_string_fast_field("key", Order::Asc);
+        let addresses = searcher.search(query, &collector)?;
+        self.documents(&searcher, addresses.into_iter().map(|(_, a)| a))
+    }
+
+    /// The first `limit` messages matching `query` in `order` of their ids.
+    fn by_id<T>(&self, query: &dyn Query, order: Order, limit: usize) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let searcher = self.reader.searcher();
+        let collector = TopDocs::with_limit(limit).order_by_fast_field::<i64>("id", order);
+        let addresses = searcher.search(query, &collector)?;
+        self.documents(&searcher, addresses.into_iter().map(|(_, a)| a))
+    }
+
+    /// The page of messages matching `query` at `offset`, ordered by `sort`. Equally
+    /// relevant messages are ordered newest first.
+    fn search<T>(
+        &self,
+        query: &dyn Query,
+        sort: SortOrder,
+        offset: usize,
+        limit: usize,
+    ) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let searcher = self.reader.searcher();
+        let top = TopDocs::with_limit(limit).and_offset(offset);
+        let addresses = match sort {
+            SortOrder::Relevance => searcher
+                .search(
+                    query,
+                    &top.tweak_score(|segment: &SegmentReader| {
+                        let date = segment
+                            .fast_fields()
+                            .i64("date")
+                            .unwrap()
+                            .first_or_default_col(0);
+                        move |doc: DocId, score: Score| (score, date.get_val(doc))
+                    }),
+                )?
+                .into_iter()
+                .map(|(_, a)| a)
+                .collect::<Vec<_>>(),
+            SortOrder::Newest | SortOrder::Oldest => searcher
+                .search(
+                    query,
+                    &top.order_by_fast_field::<i64>(
+                        "date",
+                        match sort {
+                            SortOrder::Oldest => Order::Asc,
+                            _ => Order::Desc,
+                        },
+                    ),
+                )?
+                .into_iter()
+                .map(|(_, a)| a)
+                .collect(),
+        };
+        self.documents(&searcher, addresses)
+    }
+
+    fn documents<T>(
+        &self,
+        searcher: &Searcher,
+        addresses: impl IntoIterator<Item = DocAddress>,
+    ) -> DbResult<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        addresses
+            .into_iter()
+            .map(|address| {
+                let doc = searcher.doc::<TantivyDocument>(address)?;
+                let json = doc.get_first(self.doc).and_then(|v| v.as_str());
+                Ok(serde_json::from_str(json.unwrap_or_default())?)
+            })
+            .collect()
+    }
+}
+
+impl Tokenizer for CjkTokenizer {
+    type TokenStream<'a> = CjkTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        CjkTokenStream {
+            tokens: index_tokens(text)
+                .into_iter()
+                .map(|t| Token {
+                    offset_from: t.offset_from,
+                    offset_to: t.offset_to,
+                    position: t.position,
+                    text: t.text,
+                    position_length: 1,
+                })
+                .collect(),
+            index: 0,
+        }
+    }
+}
+
+impl TokenStream for CjkTokenStream {
+    fn advance(&mut self) -> bool {
+        self.index += 1;
+        self.index <= self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.index - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.index - 1]
+    }
+}
+
+#[cfg(test)]
+mod tantivy_test {
+    use super::{embedded_test::*, *};
+
+    #[tokio::test]
+    async fn search_cjk_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "经济发展很快", 1),
+            message(2, "今天天气不错", 2),
+            message(3, "发展是硬道理", 3),
+        ]))
+        .await
+        .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("发展"), &filter(&[-1001]), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            hits.iter().map(|h| h.result.id).collect::<Vec<_>>(),
+            vec![3, 1]
+        );
+        assert!(db
+            .search_message_with_filter(&query("发展"), &filter(&[-1002]), None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn operators_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "经济发展很快", 1),
+            message(2, "今天天气不错", 2),
+            message(3, "发展是硬道理", 3),
+            message(4, "world hello", 4),
+        ]))
+        .await
+        .unwrap();
+
+        let ids = |hits: Vec<SearchHit>| hits.iter().map(|h| h.result.id).collect::<Vec<_>>();
+        for (q, expected) in [
+            (r#"hello -"hello world""#, vec![4]),
+            (r#"hello -"world hello""#, vec![]),
+            ("发展 -经济", vec![3]),
+            ("天气 OR 道理", vec![3, 2]),
+            (r#""展是""#, vec![3]),
+            ("-(发展 OR 天气)", vec![4]),
+        ] {
+            let hits = db
+                .search_message_with_filter(&query(q), &filter(&[-1001]), None)
+                .await
+                .unwrap();
+            assert_eq!(ids(hits), expected, "{q}");
+        }
+    }
+
+    #[tokio::test]
+    async fn normalized_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[Message {
+            normalized: Some("经济发展很快".to_string()),
+            ..message(1, "經濟發展很快", 1)
+        }]))
+        .await
+        .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("发展"), &filter(&[-1001]), None)
+            .await
+            .unwrap();
+        assert_eq!(hits[0].formatted_text, "經濟發展很快");
+    }
+
+    #[tokio::test]
+    async fn pinyin_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[Message {
+            variants: Some("fa zhan hen kuai fazhan zhanhen henkuai fz zh hk".to_string()),
+            ..message(1, "发展很快", 1)
+        }]))
+        .await
+        .unwrap();
+
+        for q in ["fazhan", "fz", "fazhanhenkuai", "fzhk"] {
+            let hits = db
+                .search_message_with_filter(&query(q), &filter(&[-1001]), None)
+                .await
+                .unwrap();
+            assert_eq!(hits.len(), 1, "{q}");
+        }
+    }
+
+    #[tokio::test]
+    async fn upsert_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[message(1, "旧的内容", 1)]))
+            .await
+            .unwrap();
+        db.insert_documents(Documents::Messages(&[message(1, "新的内容", 1)]))
+            .await
+            .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query("内容"), &filter(&[-1001]), None)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].formatted_text, "新的内容");
+    }
+
+    #[tokio::test]
+    async fn filter_sort_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Messages(&[
+            message(1, "发展一", 10),
+            Message {
+                sender: Some(ChatId(2)),
+                ..message(2, "发展二", 20)
+            },
+            message(3, "发展三", 30),
+            Message {
+                revised: Some(40),
+                ..message(4, "发展四", 40)
+            },
+        ]))
+        .await
+        .unwrap();
+
+        let ids = |hits: Vec<SearchHit>| hits.iter().map(|h| h.result.id).collect::<Vec<_>>();
+        for (filter, expected) in [
+            (
+                Filter {
+                    since: chrono::DateTime::from_timestamp(20, 0),
+                    sort: SortOrder::Oldest,
+                    ..filter(&[-1001])
+                },
+                vec![2, 3],
+            ),
+            (
+                Filter {
+                    sender: Some(ChatId(2)),
+                    ..filter(&[-1001])
+                },
+                vec![2],
+            ),
+            (
+                Filter {
+                    history: true,
+                    sort: SortOrder::Newest,
+                    ..filter(&[-1001])
+                },
+                vec![4, 3, 2, 1],
+            ),
+        ] {
+            let hits = db
+                .search_message_with_filter(&query("发展"), &filter, None)
+                .await
+                .unwrap();
+            assert_eq!(ids(hits), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn pagination_test() {
+        let db = Tantivy::in_ram();
+        let messages = (1..=250)
+            .map(|i| message(i, if i % 2 == 0 { "偶数" } else { "奇数" }, i.into()))
+            .collect::<Vec<_>>();
+        db.insert_documents(Documents::Messages(&messages))
+            .await
+            .unwrap();
+
+        let hits = db
+            .search_message_with_filter(&query(""), &filter(&[-1001]), Some(110))
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), INLINE_REPLY_LIMIT);
+        assert_eq!(hits[0].result.id, 140);
+        assert_eq!(
+            db.get_messages(Some(ChatId(-1001)), 240, GET_LIMIT)
+                .await
+                .unwrap()
+                .len(),
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn surrounding_test() {
+        let db = Tantivy::in_ram();
+        let mut messages = (1..=10)
+            .map(|i| message(i, "消息", i.into()))
+            .collect::<Vec<_>>();
+        messages.push(Message {
+            key: "-1001_5_r50".to_string(),
+            revised: Some(50),
+            ..message(5, "旧消息", 5)
+        });
+        messages.push(Message {
+            chat_id: ChatId(-1002),
+            key: "-1002_4".to_string(),
+            ..message(4, "消息", 4)
+        });
+        db.insert_documents(Documents::Messages(&messages))
+            .await
+            .unwrap();
+
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        for (id, n, expected) in [
+            (5, 2, vec![3, 4, 5, 6, 7]),
+            (1, 2, vec![1, 2, 3]),
+            (10, 1, vec![9, 10]),
+            (5, 0, vec![5]),
+        ] {
+            let surrounding = db
+                .get_surrounding_messages(ChatId(-1001), id, n)
+                .await
+                .unwrap();
+            assert_eq!(ids(surrounding), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn senders_test() {
+        let sender = |id, name: &str, username: Option<&str>| Sender {
+            id: ChatId(id),
+            name: name.to_string(),
+            username: username.map(ToString::to_string),
+        };
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Senders(&[
+            sender(1, "Alice Liddell", Some("alice")),
+            sender(2, "Alicia Keys", None),
+            sender(3, "张三", None),
+            sender(4, "A.B (Bot)", Some("ab_bot")),
+        ]))
+        .await
+        .unwrap();
+
+        let ids = |senders: Vec<Sender>| senders.iter().map(|s| s.id.0).collect::<Vec<_>>();
+        for (name, expected) in [
+            ("@Alice", vec![1]),
+            ("ali", vec![1, 2]),
+            ("alcia", vec![2]),
+            ("张", vec![3]),
+            ("a.b (", vec![4]),
+            ("bob", vec![]),
+        ] {
+            assert_eq!(
+                ids(db.search_senders(name).await.unwrap()),
+                expected,
+                "{name}"
+            );
+        }
+        assert_eq!(
+            db.get_sender_name(ChatId(3)).await.unwrap().unwrap(),
+            "张三"
+        );
+    }
+
+    #[tokio::test]
+    async fn topics_test() {
+        let db = Tantivy::in_ram();
+        db.insert_documents(Documents::Topics(&[
+            Topic::new(ChatId(-1001), 1, Some("一".to_string())),
+            Topic::new(ChatId(-1002), 1, Some("二".to_string())),
+        ]))
+        .await
+        .unwrap();
+
+        let topics = db.get_topics(ChatId(-1002)).await.unwrap();
+        assert_eq!(topics.len(), 1);
+        assert_eq!(topics[0].name.as_deref(), Some("二"));
+    }
+
+    #[tokio::test]
+    async fn chats_test() {
+        let db = Tantivy::in_ram();
+        db.insert_chat_with_id(ChatId(-1001)).await.unwrap();
+        db.insert_chat_with_id(ChatId(-1002)).await.unwrap();
+        db.delete_chat_with_id(ChatId(-1001)).await.unwrap();
+
+        assert!(db
+            .filter_chat_with_id(ChatId(-1001))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(db.get_all_chats().await.unwrap(), vec![ChatId(-1002)]);
+    }
+}
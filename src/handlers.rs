@@ -5,5 +5,40 @@ mod web_page_handler;
 
 pub use command_handler::{command_handler, Command};
 pub use inline_handler::inline_handler;
+pub(crate) use message_handler::index_message;
 pub use message_handler::message_handler;
-pub use web_page_handler::{get_url_in_whitelist, web_page_handler};
+pub use web_page_handler::{get_url_in_whitelist, spawn_web_page_handler, web_page_handler};
+
+use crate::db::DbError;
+use teloxide::RequestError;
+
+#[derive(Debug)]
+pub enum HandlerError {
+    Request(RequestError),
+    Db(DbError),
+}
+
+pub type HandlerResult<T> = Result<T, HandlerError>;
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::Request(e) => write!(f, "{e}"),
+            HandlerError::Db(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<RequestError> for HandlerError {
+    fn from(e: RequestError) -> Self {
+        HandlerError::Request(e)
+    }
+}
+
+impl From<DbError> for HandlerError {
+    fn from(e: DbError) -> Self {
+        HandlerError::Db(e)
+    }
+}
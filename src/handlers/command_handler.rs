@@ -1,13 +1,16 @@
-use super::inline_handler;
-use crate::db::*;
+use std::sync::Arc;
+
+use super::{inline_handler, HandlerResult};
+use crate::{config::Config, db::*, link_preview, prune::Pruner, types};
 use clap::CommandFactory;
 use teloxide::{
     prelude::*,
     types::{
-        InlineKeyboardButton, InlineKeyboardButtonKind::SwitchInlineQueryCurrentChat,
-        InlineKeyboardMarkup, ReplyParameters,
+        Chat, InlineKeyboardButton, InlineKeyboardButtonKind::SwitchInlineQueryCurrentChat,
+        InlineKeyboardMarkup, Recipient, ReplyParameters,
     },
     utils::command::BotCommands,
+    RequestError,
 };
 
 #[derive(BotCommands, Clone)]
@@ -26,6 +29,29 @@ pub enum Command {
         description = "Stop logging messages in this supergroup. You need to be an Admin or Owner to perform this action."
     )]
     Stop,
+    #[command(
+        description = "Remove messages deleted from this supergroup from search results. You need to be an Admin or Owner to perform this action."
+    )]
+    Prune,
+    #[command(
+        description = "Start logging messages in this forum topic again. You need to be an Admin or Owner to perform this action."
+    )]
+    StartTopic,
+    #[command(
+        description = "Stop logging messages in this forum topic only. You need to be an Admin or Owner to perform this action."
+    )]
+    StopTopic,
+    #[command(
+        description = "Start logging posts in the channel of this discussion group, or in a private chat, of the channel given by @username or id. You need to be an Admin or Owner of the channel to perform this action."
+    )]
+    StartChannel(String),
+    #[command(
+        description = "Stop logging posts in the channel of this discussion group, or in a private chat, of the channel given by @username or id. You need to be an Admin or Owner of the channel to perform this action."
+    )]
+    StopChannel(String),
+    // Reads the link preview settings again, for the operator of the bot only.
+    #[command(hide)]
+    Reload,
 }
 
 enum ChatAction {
@@ -34,16 +60,10 @@ enum ChatAction {
 }
 
 impl ChatAction {
-    async fn perform(&self, chat_id: ChatId) -> ResponseResult<()> {
+    async fn perform(&self, db: &Db, chat_id: ChatId) -> DbResult<()> {
         match self {
-            ChatAction::Start => {
-                Db::new().insert_chat_with_id(chat_id).await;
-                Ok(())
-            }
-            ChatAction::Stop => {
-                Db::new().delete_chat_with_id(chat_id).await;
-                Ok(())
-            }
+            ChatAction::Start => db.insert_chat_with_id(chat_id).await,
+            ChatAction::Stop => db.delete_chat_with_id(chat_id).await,
         }
     }
 
@@ -55,11 +75,28 @@ impl ChatAction {
     }
 }
 
-pub async fn command_handler(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
+pub async fn command_handler(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    db: Db,
+    pruner: Arc<Pruner>,
+    config: Arc<Config>,
+) -> HandlerResult<()> {
     match cmd {
-        Command::Help => help_handler(bot, msg).await,
-        Command::Start => chat_action_handler(bot, msg, ChatAction::Start).await,
-        Command::Stop => chat_action_handler(bot, msg, ChatAction::Stop).await,
+        Command::Help => Ok(help_handler(bot, msg).await?),
+        Command::Start => chat_action_handler(bot, msg, ChatAction::Start, db).await,
+        Command::Stop => chat_action_handler(bot, msg, ChatAction::Stop, db).await,
+        Command::Prune => prune_handler(bot, msg, db, pruner).await,
+        Command::StartTopic => topic_action_handler(bot, msg, ChatAction::Start, db).await,
+        Command::StopTopic => topic_action_handler(bot, msg, ChatAction::Stop, db).await,
+        Command::StartChannel(channel) => {
+            channel_action_handler(bot, msg, ChatAction::Start, &channel, db).await
+        }
+        Command::StopChannel(channel) => {
+            channel_action_handler(bot, msg, ChatAction::Stop, &channel, db).await
+        }
+        Command::Reload => reload_handler(bot, msg, &config).await,
     }
 }
 
@@ -86,24 +123,25 @@ To start a query, type <code>{}</code> in the text input field in any chat. Typi
     .and(Ok(()))
 }
 
-async fn chat_action_handler(bot: Bot, msg: Message, action: ChatAction) -> ResponseResult<()> {
-    if !is_privileged(&bot, &msg).await? {
+async fn chat_action_handler(
+    bot: Bot,
+    msg: Message,
+    action: ChatAction,
+    db: Db,
+) -> HandlerResult<()> {
+    if !check_admin_in_supergroup(&bot, &msg).await? {
+        Ok(())
+    } else if let Err(e) = action.perform(&db, msg.chat.id).await {
         reply_to_message(
             &bot,
             &msg,
-            "You need to be either Admin or Owner of this group to perform this action.",
+            "The database is temporarily unavailable, please try again later.",
         )
-        .await
-    } else if !msg.chat.is_supergroup() {
-        reply_to_message(&bot, &msg, "
-Commands can only be used in a supergroup.
-
-Tips: You can change a group to supergroup by setting its type to Public, and you can set it back to Private if you want.
-        ").await
+        .await?;
+        Err(e.into())
     } else {
-        action.perform(msg.chat.id).await?;
         inline_handler::clear_user_chats_cache().await;
-        reply_to_message(
+        Ok(reply_to_message(
             &bot,
             &msg,
             format!(
@@ -113,16 +151,202 @@ Tips: You can change a group to supergroup by setting its type to Public, and yo
                 action.message()
             ),
         )
-        .await
+        .await?)
+    }
+}
+
+async fn topic_action_handler(
+    bot: Bot,
+    msg: Message,
+    action: ChatAction,
+    db: Db,
+) -> HandlerResult<()> {
+    if !check_admin_in_supergroup(&bot, &msg).await? {
+        Ok(())
+    } else if db.filter_chat_with_id(msg.chat.id).await?.is_none() {
+        Ok(reply_to_message(&bot, &msg, "This chat is not logging messages.").await?)
+    } else if let Some(topic) = types::Topic::from(&msg) {
+        let stored = db.get_topic(msg.chat.id, topic.thread_id).await?;
+        let topic = types::Topic {
+            name: topic.name.or(stored.and_then(|s| s.name)),
+            disabled: matches!(action, ChatAction::Stop),
+            ..topic
+        };
+        db.insert(std::slice::from_ref(&topic)).await?;
+        Ok(reply_to_message(
+            &bot,
+            &msg,
+            format!(
+                "Topic {}({}) has {}.",
+                topic.name.unwrap_or_default(),
+                topic.thread_id,
+                action.message()
+            ),
+        )
+        .await?)
+    } else {
+        Ok(reply_to_message(
+            &bot,
+            &msg,
+            "This command can only be used in a forum topic.",
+        )
+        .await?)
+    }
+}
+
+async fn channel_action_handler(
+    bot: Bot,
+    msg: Message,
+    action: ChatAction,
+    channel: &str,
+    db: Db,
+) -> HandlerResult<()> {
+    let Some(channel) = find_channel(&bot, &msg, channel.trim()).await? else {
+        return Ok(reply_to_message(
+            &bot,
+            &msg,
+            "Channel not found. Send this command in the discussion group of a channel, or to the bot in a private chat along with the @username or id of the channel.",
+        )
+        .await?);
+    };
+
+    if !is_privileged(&bot, channel.id, &msg).await? {
+        Ok(reply_to_message(
+            &bot,
+            &msg,
+            "You need to be either Admin or Owner of this channel to perform this action. The bot needs to be an Admin of the channel as well.",
+        )
+        .await?)
+    } else if let Err(e) = action.perform(&db, channel.id).await {
+        reply_to_message(
+            &bot,
+            &msg,
+            "The database is temporarily unavailable, please try again later.",
+        )
+        .await?;
+        Err(e.into())
+    } else {
+        inline_handler::clear_user_chats_cache().await;
+        Ok(reply_to_message(
+            &bot,
+            &msg,
+            format!(
+                "Channel {}({}) has {}.",
+                channel.title().unwrap_or_default(),
+                channel.id,
+                action.message()
+            ),
+        )
+        .await?)
+    }
+}
+
+/// The channel named by `channel`, or when it is empty, the channel whose discussion group
+/// the command is sent in.
+async fn find_channel(bot: &Bot, msg: &Message, channel: &str) -> ResponseResult<Option<Chat>> {
+    let recipient: Recipient = if let Ok(id) = channel.parse() {
+        ChatId(id).into()
+    } else if channel.starts_with('@') {
+        Recipient::ChannelUsername(channel.to_string())
+    } else if channel.is_empty() && msg.chat.is_supergroup() {
+        match bot.get_chat(msg.chat.id).await?.linked_chat_id() {
+            Some(id) => ChatId(id).into(),
+            None => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+
+    match bot.get_chat(recipient).await {
+        Ok(c) => Ok(c.is_channel().then_some(c)),
+        Err(RequestError::Api(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn prune_handler(bot: Bot, msg: Message, db: Db, pruner: Arc<Pruner>) -> HandlerResult<()> {
+    if !check_admin_in_supergroup(&bot, &msg).await? {
+        Ok(())
+    } else if !pruner.is_enabled() {
+        Ok(reply_to_message(&bot, &msg, "Pruning is not enabled on this bot.").await?)
+    } else if db.filter_chat_with_id(msg.chat.id).await?.is_none() {
+        Ok(reply_to_message(&bot, &msg, "This chat is not logging messages.").await?)
+    } else {
+        reply_to_message(
+            &bot,
+            &msg,
+            "Checking for deleted messages, this may take a while.",
+        )
+        .await?;
+        // Probing is slow, do not hold up other updates of this chat meanwhile.
+        tokio::spawn(async move {
+            let text = match pruner.prune_chat(&bot, &db, msg.chat.id).await {
+                Ok(n) => format!("Removed {n} deleted messages from search results."),
+                Err(e) => {
+                    log::error!("failed to prune {}: {e}", msg.chat.id);
+                    "Failed to check for deleted messages, please try again later.".to_string()
+                }
+            };
+            if let Err(e) = reply_to_message(&bot, &msg, text).await {
+                log::warn!("failed to report pruning in {}: {e}", msg.chat.id);
+            }
+        });
+        Ok(())
+    }
+}
+
+async fn reload_handler(bot: Bot, msg: Message, config: &Config) -> HandlerResult<()> {
+    if config.operator.is_none() || msg.from.as_ref().map(|u| u.id) != config.operator {
+        reply_to_message(
+            &bot,
+            &msg,
+            "Only the operator of this bot can perform this action.",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let text = match link_preview::reload() {
+        Ok(l) => format!("Reloaded link preview settings: {l}."),
+        Err(e) => {
+            log::error!("failed to reload link preview settings: {e}");
+            "Failed to reload link preview settings, the current ones are kept.".to_string()
+        }
+    };
+    Ok(reply_to_message(&bot, &msg, text).await?)
+}
+
+/// Replies with the reason and returns `false` unless the sender may manage this chat.
+async fn check_admin_in_supergroup(bot: &Bot, msg: &Message) -> ResponseResult<bool> {
+    if !is_privileged(bot, msg.chat.id, msg).await? {
+        reply_to_message(
+            bot,
+            msg,
+            "You need to be either Admin or Owner of this group to perform this action.",
+        )
+        .await?;
+        Ok(false)
+    } else if !msg.chat.is_supergroup() {
+        reply_to_message(bot, msg, "
+Commands can only be used in a supergroup.
+
+Tips: You can change a group to supergroup by setting its type to Public, and you can set it back to Private if you want.
+        ").await?;
+        Ok(false)
+    } else {
+        Ok(true)
     }
 }
 
-async fn is_privileged(bot: &Bot, msg: &Message) -> ResponseResult<bool> {
+/// Whether the sender of `msg` may manage `chat_id`. Chats the bot cannot look into, such as
+/// channels it is not an Admin of, count as no.
+async fn is_privileged(bot: &Bot, chat_id: ChatId, msg: &Message) -> ResponseResult<bool> {
     if let Some(u) = &msg.from {
-        Ok(bot
-            .get_chat_member(msg.chat.id, u.id)
-            .await?
-            .is_privileged())
+        match bot.get_chat_member(chat_id, u.id).await {
+            Ok(m) => Ok(m.is_privileged()),
+            Err(RequestError::Api(_)) if chat_id != msg.chat.id => Ok(false),
+            Err(e) => Err(e),
+        }
     } else {
         Ok(false)
     }
@@ -1,24 +1,38 @@
-use crate::{db::*, types};
+use super::{HandlerError, HandlerResult};
+use crate::{
+    date::DateRange,
+    db::*,
+    normalize::normalizer,
+    query::{split_words, Expr},
+    types,
+};
 use cached::{proc_macro::cached, Cached};
 use clap::{CommandFactory, Parser};
 use futures::{StreamExt, TryStreamExt};
 use teloxide::{
     prelude::*,
     types::{
-        InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+        InlineKeyboardButton, InlineKeyboardButtonKind::SwitchInlineQueryCurrentChat,
+        InlineKeyboardMarkup, InlineQueryResult, InlineQueryResultArticle,
+        InlineQueryResultCachedPhoto, InputMessageContent, InputMessageContentText,
         ParseMode::Html,
     },
     ApiError, RequestError,
 };
 
 const MAX_MESSAGE_LENGTH: usize = 3072;
+/// Telegram's limit on the visible text of a caption, in UTF-16 code units.
+const MAX_CAPTION_LENGTH: usize = 1024;
+const ELLIPSIS: &str = "...";
+/// Characters of each message kept in a `--context` transcript.
+const CONTEXT_LINE_LENGTH: usize = 200;
 
 #[derive(Parser)]
 #[command(name = crate::BOT_USERNAME.get().unwrap())]
 #[command(disable_version_flag = true, disable_help_flag = true)]
 #[command(author, version, long_about = None)]
 pub struct Cli {
-    /// Keywords to search
+    /// Keywords to search. Use "quoted phrases", -word to exclude, a OR b, and (groups)
     #[arg(default_value = "", hide_default_value = true)]
     query: Vec<String>,
 
@@ -45,34 +59,182 @@ pub struct Cli {
     /// Only search for web pages
     #[arg(short = 'w', long, conflicts_with = "no_urls")]
     only_urls: bool,
+
+    /// Only search for messages sent since a date (2024-01-31, 2024-01) or a while ago (7d, 3m)
+    #[arg(short, long, value_name = "DATE")]
+    since: Option<DateRange>,
+
+    /// Only search for messages sent until a date (2024-01-31, 2024-01) or a while ago (7d, 3m)
+    #[arg(short, long, value_name = "DATE")]
+    until: Option<DateRange>,
+
+    /// Only search for messages from a sender, by name, @username or id
+    #[arg(short, long, value_name = "SENDER", allow_negative_numbers = true)]
+    from: Option<String>,
+
+    /// Only search for messages forwarded from a user or chat, by name, @username or id
+    #[arg(long, value_name = "SENDER", allow_negative_numbers = true)]
+    forwarded_from: Option<String>,
+
+    /// Only search for replies to a message, by its link, or its id along with a single --chat
+    #[arg(long, value_name = "MESSAGE")]
+    replies_to: Option<String>,
+
+    /// Only search in chats whose title contains this or with this id, can be repeated
+    #[arg(short, long, value_name = "CHAT", allow_negative_numbers = true)]
+    chat: Vec<String>,
+
+    /// Do not search in chats whose title contains this or with this id, can be repeated
+    #[arg(short = 'x', long, value_name = "CHAT", allow_negative_numbers = true)]
+    exclude_chat: Vec<String>,
+
+    /// Only search in forum topics whose name contains this or with this id, can be repeated
+    #[arg(short, long, value_name = "TOPIC")]
+    topic: Vec<String>,
+
+    /// Only search for messages of this type, can be repeated
+    #[arg(long = "type", value_enum, value_name = "TYPE")]
+    kind: Vec<types::MessageKind>,
+
+    /// Also search earlier versions of edited messages, and show their original text
+    #[arg(long)]
+    history: bool,
+
+    /// Send the N messages before and after each result along with it
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=5))]
+    context: Option<u8>,
+
+    /// Order of search results
+    #[arg(long, value_enum, default_value_t)]
+    sort: SortOrder,
 }
 
-pub async fn inline_handler(bot: Bot, q: InlineQuery) -> ResponseResult<()> {
+pub async fn inline_handler(bot: Bot, q: InlineQuery, db: Db) -> HandlerResult<()> {
     log::debug!("{}", serde_json::to_string_pretty(&q).unwrap());
-    match Cli::try_parse_from([vec![""], (q.query.split_whitespace().collect::<Vec<_>>())].concat())
-    {
-        Ok(cli) => parsed_handler(bot, q, cli).await,
-        Err(e) => parse_error_handler(bot, q, e).await,
+    match parse_query(&q.query) {
+        Ok((cli, query)) => match parsed_handler(bot.clone(), &q, cli, &query, &db).await {
+            Err(HandlerError::Db(e)) => {
+                unavailable_handler(bot, &q).await?;
+                Err(e.into())
+            }
+            r => r,
+        },
+        Err(e) => Ok(parse_error_handler(bot, q, e).await?),
     }
 }
 
-async fn parsed_handler(bot: Bot, q: InlineQuery, cli: Cli) -> ResponseResult<()> {
-    let search_filter = construct_filter(bot.clone(), &q, &cli).await?;
+/// Splits the options from the keywords, since excluded keywords look just like options
+/// to clap, and parses both. Words starting with a short option, like `-am` or `-s7d`, are
+/// options. Quoted words are always keywords, so `-"s"` excludes the word `s` rather than
+/// starting `--since`.
+fn parse_query(query: &str) -> Result<(Cli, Expr), clap::Error> {
+    let command = Cli::command();
+    let long = |word: &str| {
+        let name = word.strip_prefix("--")?;
+        command.get_arguments().find(|a| a.get_long() == Some(name))
+    };
+    let short = |c: char| command.get_arguments().find(|a| a.get_short() == Some(c));
+
+    let (mut args, mut keywords) = (vec![String::new()], vec![]);
+    let mut words = split_words(query).into_iter();
+    while let Some((w, quoted)) = words.next() {
+        if quoted {
+            keywords.push(w);
+        } else if w == "--" {
+            keywords.extend(words.by_ref().map(|(w, _)| w));
+        } else if w.starts_with("--") && w.contains('=') {
+            args.push(w.to_string());
+        } else if let Some(arg) = long(w) {
+            args.push(w.to_string());
+            if arg.get_action().takes_values() {
+                // Values may be quoted to keep their spaces, as in `--chat "Book Club"`.
+                args.extend(words.next().map(|(v, _)| v.trim_matches('"').to_string()));
+            }
+        } else if w.starts_with("--") {
+            // Unknown options are left for clap to complain about.
+            args.push(w.to_string());
+        } else if let Some(flags) = w
+            .strip_prefix('-')
+            .filter(|f| f.chars().next().and_then(short).is_some())
+        {
+            args.push(w.to_string());
+            // The first option taking a value ends the group, with the rest of the word or
+            // the next word as its value. Unknown options are left for clap to complain about.
+            let value_next = flags.char_indices().find_map(|(i, c)| match short(c) {
+                Some(a) if a.get_action().takes_values() => Some(i + c.len_utf8() == flags.len()),
+                Some(_) => None,
+                None => Some(false),
+            });
+            if value_next == Some(true) {
+                args.extend(words.next().map(|(v, _)| v.trim_matches('"').to_string()));
+            }
+        } else {
+            keywords.push(w);
+        }
+    }
+    args.push("--".to_string());
+    args.extend(keywords.iter().map(ToString::to_string));
+
+    let cli = Cli::try_parse_from(args)?;
+    let expr = Expr::parse(&normalizer().normalize(&keywords.join(" "))).map_err(|e| {
+        command
+            .clone()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+    })?;
+    Ok((cli, expr))
+}
+
+async fn parsed_handler(
+    bot: Bot,
+    q: &InlineQuery,
+    cli: Cli,
+    query: &Expr,
+    db: &Db,
+) -> HandlerResult<()> {
+    let sender = match &cli.from {
+        Some(from) => match resolve_sender(from, db).await? {
+            Ok(id) => Some(id),
+            Err(candidates) => {
+                return Ok(sender_suggestion_handler(bot, q, "--from", from, candidates).await?);
+            }
+        },
+        None => None,
+    };
+    let forwarded_from = match &cli.forwarded_from {
+        Some(from) => match resolve_sender(from, db).await? {
+            Ok(id) => Some(id),
+            Err(candidates) => {
+                return Ok(
+                    sender_suggestion_handler(bot, q, "--forwarded-from", from, candidates).await?,
+                );
+            }
+        },
+        None => None,
+    };
+    let replies_to = match &cli.replies_to {
+        Some(r) => match resolve_message(r, db).await? {
+            Some(m) => Some(m),
+            None => return Ok(invalid_message_handler(bot, q, r).await?),
+        },
+        None => None,
+    };
+    let Some(search_filter) =
+        construct_filter(bot.clone(), q, &cli, sender, forwarded_from, replies_to, db).await?
+    else {
+        let r = cli.replies_to.as_deref().unwrap_or_default();
+        return Ok(invalid_message_handler(bot, q, r).await?);
+    };
     let current_offset: Option<usize> = q.offset.parse::<usize>().ok();
 
-    let search_results = Db::new()
-        .search_message_with_filter(&cli.query.join(" "), &search_filter, current_offset)
-        .await;
-    let mut results = futures::stream::iter(search_results.hits.into_iter().map(|m| {
-        (
-            m.result,
-            m.formatted_result.unwrap()["text"]
-                .as_str()
-                .unwrap()
-                .to_string(),
-        )
-    }))
-    .then(|(m, f)| construct_query_result(bot.clone(), m, f))
+    let search_results = db
+        .search_message_with_filter(query, &search_filter, current_offset)
+        .await?;
+    let mut results = futures::stream::iter(
+        search_results
+            .into_iter()
+            .map(|m| (m.result, m.formatted_text)),
+    )
+    .then(|(m, f)| construct_query_result(bot.clone(), m, f, &cli, db))
     .try_collect::<Vec<_>>()
     .await?;
 
@@ -96,8 +258,30 @@ async fn parsed_handler(bot: Bot, q: InlineQuery, cli: Cli) -> ResponseResult<()
         .next_offset(next_offset)
         .cache_time(0)
         .send()
-        .await
-        .and(Ok(()))
+        .await?;
+    Ok(())
+}
+
+/// Answers with a single article instead of leaving the query spinning while the database
+/// cannot be reached.
+async fn unavailable_handler(bot: Bot, q: &InlineQuery) -> ResponseResult<()> {
+    let title = "Search temporarily unavailable.";
+    bot.answer_inline_query(
+        &q.id,
+        [InlineQueryResult::Article(
+            InlineQueryResultArticle::new(
+                "unavailable",
+                title,
+                InputMessageContent::Text(InputMessageContentText::new(title)),
+            )
+            .description("Please try again later."),
+        )],
+    )
+    .next_offset("")
+    .cache_time(0)
+    .send()
+    .await
+    .and(Ok(()))
 }
 
 async fn parse_error_handler(bot: Bot, q: InlineQuery, e: clap::Error) -> ResponseResult<()> {
@@ -134,6 +318,169 @@ async fn parse_error_handler(bot: Bot, q: InlineQuery, e: clap::Error) -> Respon
     .and(Ok(()))
 }
 
+/// Answers with the senders the value of `option`, `from`, may have meant, each with a
+/// button to search again with its id.
+async fn sender_suggestion_handler(
+    bot: Bot,
+    q: &InlineQuery,
+    option: &str,
+    from: &str,
+    candidates: Vec<types::Sender>,
+) -> ResponseResult<()> {
+    let results = if candidates.is_empty() {
+        let title = format!("No sender matches {from}.");
+        vec![InlineQueryResult::Article(InlineQueryResultArticle::new(
+            "no_sender",
+            &title,
+            InputMessageContent::Text(InputMessageContentText::new(&title)),
+        ))]
+    } else {
+        candidates
+            .into_iter()
+            .map(|s| {
+                let name = match &s.username {
+                    Some(u) => format!("{} (@{u})", s.name),
+                    None => s.name.clone(),
+                };
+                InlineQueryResult::Article(
+                    InlineQueryResultArticle::new(
+                        format!("sender_{}", s.id),
+                        &name,
+                        InputMessageContent::Text(
+                            InputMessageContentText::new(format!(
+                                "Search messages from {} with <code>{option} {}</code>.",
+                                html_escape::encode_text(&name),
+                                s.id
+                            ))
+                            .parse_mode(Html),
+                        ),
+                    )
+                    .description(format!("Did you mean {option} {}?", s.id))
+                    .reply_markup(InlineKeyboardMarkup::new([[InlineKeyboardButton::new(
+                        "Search",
+                        SwitchInlineQueryCurrentChat(replace_option(
+                            &q.query,
+                            option,
+                            &s.id.to_string(),
+                        )),
+                    )]])),
+                )
+            })
+            .collect()
+    };
+
+    bot.answer_inline_query(&q.id, results)
+        .next_offset("")
+        .cache_time(0)
+        .send()
+        .await
+        .and(Ok(()))
+}
+
+/// Looks `from` up as an id, an @username or a name. Yields the candidates instead when it
+/// does not single out one sender.
+async fn resolve_sender(from: &str, db: &Db) -> DbResult<Result<ChatId, Vec<types::Sender>>> {
+    if let Ok(id) = from.parse() {
+        return Ok(Ok(ChatId(id)));
+    }
+
+    let candidates = db.search_senders(from).await?;
+    let exact = candidates
+        .iter()
+        .filter(|s| match from.strip_prefix('@') {
+            Some(u) => s
+                .username
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(u)),
+            None => s.name.to_lowercase() == from.to_lowercase(),
+        })
+        .collect::<Vec<_>>();
+    Ok(match (exact.as_slice(), candidates.as_slice()) {
+        ([s], _) => Ok(s.id),
+        ([], [s]) => Ok(s.id),
+        _ => Err(candidates),
+    })
+}
+
+/// Rewrites the value of the long `option`, or of its short form, in `query`.
+fn replace_option(query: &str, option: &str, value: &str) -> String {
+    let short = Cli::command()
+        .get_arguments()
+        .find(|a| a.get_long() == option.strip_prefix("--"))
+        .and_then(|a| a.get_short())
+        .map(|c| format!("-{c}"));
+    let mut words = split_words(query)
+        .into_iter()
+        .map(|(w, _)| w.to_string())
+        .collect::<Vec<_>>();
+    for i in 0..words.len() {
+        if words[i].starts_with(&format!("{option}=")) {
+            words[i] = format!("{option}={value}");
+        } else if (short.as_ref() == Some(&words[i]) || words[i] == option) && i + 1 < words.len() {
+            words[i + 1] = value.to_string();
+        }
+    }
+    words.join(" ")
+}
+
+/// Answers that `value` of `--replies-to` is neither a message id nor a message link.
+async fn invalid_message_handler(bot: Bot, q: &InlineQuery, value: &str) -> ResponseResult<()> {
+    let title = format!("No message matches {value}.");
+    bot.answer_inline_query(
+        &q.id,
+        [InlineQueryResult::Article(
+            InlineQueryResultArticle::new(
+                "no_message",
+                &title,
+                InputMessageContent::Text(InputMessageContentText::new(&title)),
+            )
+            .description(
+                "Use a message link, like https://t.me/c/1234/56, or an id along with a single --chat.",
+            ),
+        )],
+    )
+    .next_offset("")
+    .cache_time(0)
+    .send()
+    .await
+    .and(Ok(()))
+}
+
+/// Reads a message id, or a message link along with its chat when the link tells it.
+async fn resolve_message(value: &str, db: &Db) -> DbResult<Option<(Option<ChatId>, i32)>> {
+    if let Ok(id) = value.parse() {
+        return Ok(Some((None, id)));
+    }
+
+    let Some(path) = reqwest::Url::parse(value)
+        .ok()
+        .filter(|u| matches!(u.host_str(), Some("t.me" | "telegram.me")))
+        .and_then(|u| {
+            u.path_segments()
+                .map(|s| s.map(ToString::to_string).collect::<Vec<_>>())
+        })
+    else {
+        return Ok(None);
+    };
+    let Some(Ok(id)) = path.last().map(|id| id.parse()) else {
+        return Ok(None);
+    };
+    // Links are `c/<chat>/[<thread>/]<id>` for private chats and `<username>/[<thread>/]<id>`
+    // for public ones.
+    let chat = match path.first().map(String::as_str) {
+        Some("c") => path
+            .get(1)
+            .and_then(|c| format!("-100{c}").parse().ok())
+            .map(ChatId),
+        Some(username) => match resolve_sender(&format!("@{username}"), db).await? {
+            Ok(c) => Some(c),
+            Err(_) => return Ok(None),
+        },
+        None => None,
+    };
+    Ok(chat.map(|c| (Some(c), id)))
+}
+
 async fn is_chat_member_present(
     bot: Bot,
     chat_id: ChatId,
@@ -154,9 +501,9 @@ async fn is_chat_member_present(
     key = "UserId",
     convert = r#"{ user_id }"#
 )]
-async fn get_user_chats(bot: Bot, user_id: UserId) -> ResponseResult<Vec<types::Chat>> {
+async fn get_user_chats(bot: Bot, user_id: UserId, db: &Db) -> HandlerResult<Vec<types::Chat>> {
     log::debug!("uncached get_user_chats {}", user_id);
-    futures::stream::iter(Db::new().get_all_chats().await)
+    Ok(futures::stream::iter(db.get_all_chats().await?)
         .filter_map(|chat| {
             let bot = bot.clone();
             async move {
@@ -168,7 +515,7 @@ async fn get_user_chats(bot: Bot, user_id: UserId) -> ResponseResult<Vec<types::
             }
         })
         .try_collect()
-        .await
+        .await?)
 }
 
 #[cached(
@@ -179,7 +526,7 @@ async fn get_user_chats(bot: Bot, user_id: UserId) -> ResponseResult<Vec<types::
     key = "ChatId",
     convert = r#"{ chat_id }"#
 )]
-async fn get_name_from_tg(bot: Bot, chat_id: ChatId) -> ResponseResult<Option<String>> {
+async fn get_sender_from_tg(bot: Bot, chat_id: ChatId) -> ResponseResult<Option<types::Sender>> {
     bot.get_chat(chat_id).await.map_or_else(
         |e| {
             if let RequestError::Api(ApiError::ChatNotFound) = e {
@@ -194,7 +541,12 @@ async fn get_name_from_tg(bot: Bot, chat_id: ChatId) -> ResponseResult<Option<St
                 .or(c.first_name().map(|first_name| match c.last_name() {
                     Some(last_name) => format!("{} {}", first_name, last_name),
                     None => first_name.to_string(),
-                })))
+                }))
+                .map(|name| types::Sender {
+                    id: chat_id,
+                    name,
+                    username: c.username().map(ToString::to_string),
+                }))
         },
     )
 }
@@ -206,43 +558,43 @@ async fn get_name_from_tg(bot: Bot, chat_id: ChatId) -> ResponseResult<Option<St
     key = "ChatId",
     convert = r#"{ chat_id }"#
 )]
-async fn get_name_from_chat_id(bot: Bot, chat_id: ChatId) -> ResponseResult<String> {
-    if let Some(n) = Db::new().get_sender_name(chat_id).await {
+async fn get_name_from_chat_id(bot: Bot, chat_id: ChatId, db: &Db) -> HandlerResult<String> {
+    if let Some(n) = db.get_sender_name(chat_id).await? {
+        let db = db.clone();
         tokio::spawn(async move {
-            if let Some(n) = get_name_from_tg(bot, chat_id).await.unwrap_or(None) {
-                Db::new()
-                    .insert(&vec![types::Sender {
-                        id: chat_id,
-                        name: n,
-                    }])
-                    .await;
+            if let Some(s) = get_sender_from_tg(bot, chat_id).await.unwrap_or(None) {
+                if let Err(e) = db.insert(&[s]).await {
+                    log::warn!("failed to refresh sender name of {chat_id}: {e}");
+                }
             }
         });
         Ok(n)
     } else {
-        match get_name_from_tg(bot, chat_id).await? {
-            Some(n) => {
-                Db::new()
-                    .insert(&vec![types::Sender {
-                        id: chat_id,
-                        name: n.clone(),
-                    }])
-                    .await;
-                Ok(n)
+        match get_sender_from_tg(bot, chat_id).await? {
+            Some(s) => {
+                db.insert(std::slice::from_ref(&s)).await?;
+                Ok(s.name)
             }
             None => Ok("Anonymous".to_string()),
         }
     }
 }
 
-async fn generate_from_str(bot: Bot, m: &types::Message) -> ResponseResult<String> {
+async fn generate_from_str(bot: Bot, m: &types::Message, db: &Db) -> HandlerResult<String> {
     if let Some(from) = &m.from {
         Ok(from.clone())
+    } else if m.sender.is_none_or(|s| s == m.chat_id) {
+        // Posts in channels, and by anonymous admins, come from the chat itself.
+        get_name_from_chat_id(bot, m.chat_id, db).await
     } else {
         Ok(format!(
-            "{}@{}",
-            get_name_from_chat_id(bot.clone(), m.sender.unwrap()).await?,
-            get_name_from_chat_id(bot.clone(), m.chat_id).await?
+            "{}@{}{}",
+            get_name_from_chat_id(bot.clone(), m.sender.unwrap_or(m.chat_id), db).await?,
+            get_name_from_chat_id(bot.clone(), m.chat_id, db).await?,
+            m.topic
+                .as_ref()
+                .map(|t| format!(" › {t}"))
+                .unwrap_or_default()
         ))
     }
 }
@@ -251,9 +603,33 @@ async fn construct_filter<'a>(
     bot: Bot,
     q: &'a InlineQuery,
     cli: &'a Cli,
-) -> ResponseResult<Filter<'a>> {
-    Ok(Filter {
-        chats: get_user_chats(bot, q.from.id).await?,
+    sender: Option<ChatId>,
+    forwarded_from: Option<ChatId>,
+    replies_to: Option<(Option<ChatId>, i32)>,
+    db: &Db,
+) -> HandlerResult<Option<Filter<'a>>> {
+    let mut chats = narrow_chats(
+        bot.clone(),
+        get_user_chats(bot, q.from.id, db).await?,
+        cli,
+        db,
+    )
+    .await?;
+    // Message ids are only unique within a chat, so a bare id needs a single chat to search.
+    let replies_to = match replies_to {
+        Some((Some(chat), id)) => {
+            chats.retain(|c| c.id == chat);
+            Some((chat, id))
+        }
+        Some((None, id)) => match chats.as_slice() {
+            [c] => Some((c.id, id)),
+            _ => return Ok(None),
+        },
+        None => None,
+    };
+    Ok(Some(Filter {
+        topics: resolve_topics(&chats, cli, db).await?,
+        chats,
         include_bots: if cli.include_all_bots || cli.only_all_bots || cli.only_bots.is_some() {
             FilterOption::All
         } else {
@@ -277,40 +653,189 @@ async fn construct_filter<'a>(
         } else {
             EnableOption::Enable
         },
-    })
+        since: cli.since.map(|d| d.start),
+        until: cli.until.map(|d| d.end),
+        sender,
+        kinds: (!cli.kind.is_empty()).then(|| cli.kind.clone()),
+        forwarded_from,
+        replies_to,
+        history: cli.history,
+        sort: cli.sort,
+    }))
+}
+
+/// Applies `--chat` and `--exclude-chat` to the chats the user may search, which can only
+/// ever drop some of them.
+async fn narrow_chats(
+    bot: Bot,
+    chats: Vec<types::Chat>,
+    cli: &Cli,
+    db: &Db,
+) -> HandlerResult<Vec<types::Chat>> {
+    if cli.chat.is_empty() && cli.exclude_chat.is_empty() {
+        return Ok(chats);
+    }
+
+    let mut res = vec![];
+    for c in chats {
+        let title = get_name_from_chat_id(bot.clone(), c.id, db)
+            .await?
+            .to_lowercase();
+        let matches =
+            |value: &String| c.id.to_string() == *value || title.contains(&value.to_lowercase());
+        if (cli.chat.is_empty() || cli.chat.iter().any(matches))
+            && !cli.exclude_chat.iter().any(matches)
+        {
+            res.push(c);
+        }
+    }
+    Ok(res)
+}
+
+/// Looks up the forum topics `--topic` stands for in `chats`, `None` when it is not given.
+async fn resolve_topics(
+    chats: &[types::Chat],
+    cli: &Cli,
+    db: &Db,
+) -> DbResult<Option<Vec<(ChatId, i32)>>> {
+    if cli.topic.is_empty() {
+        return Ok(None);
+    }
+
+    let mut res = vec![];
+    for c in chats {
+        for t in db.get_topics(c.id).await? {
+            let name = t.name.unwrap_or_default().to_lowercase();
+            if cli.topic.iter().any(|value| {
+                t.thread_id.to_string() == *value || name.contains(&value.to_lowercase())
+            }) {
+                res.push((c.id, t.thread_id));
+            }
+        }
+    }
+    Ok(Some(res))
 }
 
 async fn construct_query_result(
     bot: Bot,
     m: types::Message,
     formatted_result: String,
-) -> ResponseResult<InlineQueryResult> {
+    cli: &Cli,
+    db: &Db,
+) -> HandlerResult<InlineQueryResult> {
+    // Photos are sent with the text as their caption, which is shorter than a message.
+    let photo = m
+        .photo_file_id
+        .clone()
+        .filter(|_| m.web_page.is_none() && cli.context.is_none());
+    let from = generate_from_str(bot.clone(), &m, db).await?;
+    let header = format!(
+        r#" from <a href="{}">{}</a>{}{}"#,
+        m.link(),
+        html_escape::encode_text(&from),
+        generate_forward_html(&m),
+        generate_in_url_html(&m),
+    );
+    let original = m.original.as_ref().filter(|_| cli.history);
+    let (text_length, original_length) = match photo {
+        Some(_) => caption_budget(&header, &m.text, original.map(String::as_str)),
+        None => (MAX_MESSAGE_LENGTH, MAX_MESSAGE_LENGTH),
+    };
+    let quote = match cli.context {
+        Some(n) => generate_context_html(bot.clone(), &m, n.into(), db).await?,
+        None => format!(
+            "「 {} 」",
+            html_escape::encode_text(&limit_string_length(m.text.clone(), text_length))
+        ),
+    };
+    let text = format!(
+        "{}{}{}",
+        quote,
+        header,
+        match original {
+            Some(o) => format!(
+                "\nOriginally: 「 {} 」",
+                html_escape::encode_text(&limit_string_length(o.clone(), original_length))
+            ),
+            None => String::default(),
+        }
+    );
+    let description = format!(
+        "{}@{}{}{}{}{}",
+        from,
+        m.format_time(),
+        generate_forward_desc(&m),
+        generate_reply_desc(&m),
+        generate_in_url_desc(&m),
+        generate_revision_desc(&m)
+    );
+
+    if let Some(f) = photo {
+        return Ok(InlineQueryResult::CachedPhoto(
+            InlineQueryResultCachedPhoto::new(&m.key, f)
+                .title(formatted_result)
+                .description(description)
+                .caption(text)
+                .parse_mode(Html),
+        ));
+    }
     let mut article = InlineQueryResultArticle::new(
         &m.key,
         formatted_result,
-        InputMessageContent::Text(
-            InputMessageContentText::new(format!(
-                r#"「 {} 」 from <a href="{}">{}</a>{}"#,
-                html_escape::encode_text(&limit_string_length(m.text.clone())),
-                m.link(),
-                html_escape::encode_text(&generate_from_str(bot.clone(), &m).await?),
-                generate_in_url_html(&m)
-            ))
-            .parse_mode(Html),
-        ),
+        InputMessageContent::Text(InputMessageContentText::new(text).parse_mode(Html)),
     )
-    .description(format!(
-        "{}@{}{}",
-        generate_from_str(bot.clone(), &m).await?,
-        m.format_time(),
-        generate_in_url_desc(&m)
-    ));
+    .description(description);
     if let Some(u) = m.thumbnail_url {
         article = article.thumbnail_url(u);
     }
     Ok(InlineQueryResult::Article(article))
 }
 
+/// How much of `text` and of `original` fit in a caption after `header`, with the markup
+/// counted as if it were visible. `text` gets what `original` leaves of the larger half.
+fn caption_budget(header: &str, text: &str, original: Option<&str>) -> (usize, usize) {
+    let utf16_length = |s: &str| s.encode_utf16().count();
+    let fixed = utf16_length("「  」") + utf16_length(ELLIPSIS) + utf16_length(header);
+    let (budget, original_length) = match original {
+        Some(o) => (
+            MAX_CAPTION_LENGTH.saturating_sub(
+                fixed + utf16_length("\nOriginally: 「  」") + utf16_length(ELLIPSIS),
+            ),
+            utf16_length(o),
+        ),
+        None => (MAX_CAPTION_LENGTH.saturating_sub(fixed), 0),
+    };
+    let text_budget = budget - original_length.min(budget / 2);
+    (text_budget, budget - utf16_length(text).min(text_budget))
+}
+
+/// The messages around `m` as a transcript of `name: text` lines, with `m` in bold.
+async fn generate_context_html(
+    bot: Bot,
+    m: &types::Message,
+    n: usize,
+    db: &Db,
+) -> HandlerResult<String> {
+    let mut lines = vec![];
+    for c in db.get_surrounding_messages(m.chat_id, m.id, n).await? {
+        let name = match &c.from {
+            Some(from) => from.clone(),
+            // Without a sender the message was posted as the chat itself.
+            None => get_name_from_chat_id(bot.clone(), c.sender.unwrap_or(c.chat_id), db).await?,
+        };
+        let line = format!(
+            "{}: {}",
+            html_escape::encode_text(&name),
+            html_escape::encode_text(&types::snippet(&c.text, CONTEXT_LINE_LENGTH))
+        );
+        lines.push(match c.id == m.id {
+            true => format!("<b>{line}</b>"),
+            false => line,
+        });
+    }
+    Ok(format!("{}\n\n—", lines.join("\n")))
+}
+
 fn generate_in_url_html(msg: &types::Message) -> String {
     if let Some(u) = &msg.web_page {
         format!(r#" in <a href="{}">{}</a>"#, u.as_str(), u.as_str())
@@ -327,19 +852,63 @@ fn generate_in_url_desc(msg: &types::Message) -> String {
     }
 }
 
-fn limit_string_length(input: String) -> String {
-    if input.chars().count() <= MAX_MESSAGE_LENGTH {
+fn generate_forward_html(msg: &types::Message) -> String {
+    match (&msg.forward_from, &msg.forward_link) {
+        (Some(f), Some(u)) => format!(
+            r#", forwarded from <a href="{}">{}</a>"#,
+            u.as_str(),
+            html_escape::encode_text(f)
+        ),
+        (Some(f), None) => format!(", forwarded from {}", html_escape::encode_text(f)),
+        _ => String::default(),
+    }
+}
+
+fn generate_forward_desc(msg: &types::Message) -> String {
+    match &msg.forward_from {
+        Some(f) => format!(", forwarded from {f}"),
+        None => String::default(),
+    }
+}
+
+fn generate_reply_desc(msg: &types::Message) -> String {
+    match (&msg.reply_snippet, msg.reply_to) {
+        (Some(s), _) => format!(", in reply to 「{s}」"),
+        (None, Some(_)) => ", in reply".to_string(),
+        _ => String::default(),
+    }
+}
+
+fn generate_revision_desc(msg: &types::Message) -> String {
+    match msg
+        .revised
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+    {
+        Some(t) => format!(
+            " (edited {})",
+            t.with_timezone(&crate::date::local_tz()).format("%Y-%m-%d")
+        ),
+        None => String::default(),
+    }
+}
+
+/// Cuts `input` down to `max_length` UTF-16 code units, the way Telegram counts them, and
+/// marks the cut with an ellipsis.
+fn limit_string_length(input: String, max_length: usize) -> String {
+    if input.encode_utf16().count() <= max_length {
         return input;
     }
 
-    let mut truncated = String::with_capacity(MAX_MESSAGE_LENGTH + 3);
-    for (i, c) in input.chars().enumerate() {
-        if i >= MAX_MESSAGE_LENGTH {
+    let mut truncated = String::with_capacity(max_length + ELLIPSIS.len());
+    let mut length = 0;
+    for c in input.chars() {
+        length += c.len_utf16();
+        if length > max_length {
             break;
         }
         truncated.push(c);
     }
-    truncated.push_str("...");
+    truncated.push_str(ELLIPSIS);
 
     truncated
 }
@@ -1,43 +1,166 @@
-use super::{command_handler, web_page_handler};
-use crate::{db::*, handlers::command_handler::help_handler, types};
+use std::sync::Arc;
+
+use super::{command_handler, spawn_web_page_handler, HandlerResult};
+use crate::{
+    config::Config, db::*, handlers::command_handler::help_handler, prune::Pruner,
+    queue::IndexQueue, types,
+};
 use teloxide::{prelude::*, types::Me, utils::command::BotCommands};
 
-pub async fn message_handler(bot: Bot, msg: Message, me: Me) -> ResponseResult<()> {
+pub async fn message_handler(
+    bot: Bot,
+    msg: Message,
+    me: Me,
+    db: Db,
+    queue: Arc<IndexQueue>,
+    pruner: Arc<Pruner>,
+    config: Arc<Config>,
+) -> HandlerResult<()> {
     log::debug!("{}", serde_json::to_string_pretty(&msg).unwrap());
 
-    if msg.thread_id.is_some()
-        || !msg.chat.is_chat()
-        || msg.via_bot.as_ref().is_some_and(|b| b.id == me.id)
-    {
+    if msg.via_bot.as_ref().is_some_and(|b| b.id == me.id) {
         Ok(())
+    } else if msg.chat.is_channel() {
+        // Channels have no commands, they are enabled from their discussion group instead.
+        match types::Message::text_of(&msg) {
+            Some(_) => normal_message_handler(msg, &db, &queue, &config).await,
+            None => Ok(()),
+        }
     } else if msg.chat.is_private() {
-        if msg.edit_date().is_none() {
-            help_handler(bot, msg).await
-        } else {
-            Ok(())
+        match msg
+            .text()
+            .map(|t| command_handler::Command::parse(t, me.username()))
+        {
+            Some(Ok(
+                cmd @ (command_handler::Command::StartChannel(_)
+                | command_handler::Command::StopChannel(_)
+                | command_handler::Command::Reload),
+            )) => command_handler(bot, msg, cmd, db, pruner, config).await,
+            _ if msg.edit_date().is_none() => Ok(help_handler(bot, msg).await?),
+            _ => Ok(()),
         }
     } else if let Some(text) = msg.text() {
         match command_handler::Command::parse(text, me.username()) {
-            Ok(cmd) => command_handler(bot, msg, cmd).await,
-            Err(_) => normal_message_handler(msg).await,
+            Ok(cmd) => command_handler(bot, msg, cmd, db, pruner, config).await,
+            Err(_) => normal_message_handler(msg, &db, &queue, &config).await,
         }
-    } else if msg.caption().is_some() {
-        normal_message_handler(msg).await
+    } else if msg.forum_topic_created().is_some() || msg.forum_topic_edited().is_some() {
+        Ok(topic_handler(&db, &msg).await?)
+    } else if types::Message::text_of(&msg).is_some() {
+        normal_message_handler(msg, &db, &queue, &config).await
     } else {
         Ok(())
     }
 }
 
-async fn normal_message_handler(msg: Message) -> ResponseResult<()> {
-    if !msg.chat.is_supergroup() || Db::new().filter_chat_with_id(msg.chat.id).await.is_none() {
+async fn normal_message_handler(
+    msg: Message,
+    db: &Db,
+    queue: &IndexQueue,
+    config: &Config,
+) -> HandlerResult<()> {
+    if !msg.chat.is_supergroup() && !msg.chat.is_channel() {
         log::debug!("{} not a enabled chat", &msg.chat.id);
         return Ok(());
     }
 
-    Db::new().insert(&types::Sender::from(&msg)).await;
-    Db::new().insert(&vec![types::Message::from(&msg)]).await;
+    // Only enabled chats are queued. When that cannot be told, queue the message anyway, it
+    // is checked again when indexed.
+    match db.filter_chat_with_id(msg.chat.id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            log::debug!("{} not a enabled chat", &msg.chat.id);
+            return Ok(());
+        }
+        Err(e) => log::warn!(
+            "failed to look up chat {}, queueing anyway: {e}",
+            msg.chat.id
+        ),
+    }
+
+    if let Err(e) = queue.push(&msg).await {
+        log::error!("failed to queue message {} in {}: {e}", msg.id, msg.chat.id);
+        return Ok(index_message(db, config, &msg).await?);
+    }
+    if let Err(e) = queue.drain().await {
+        log::warn!(
+            "failed to index message {} in {}, will retry: {e}",
+            msg.id,
+            msg.chat.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Remembers the name of a forum topic when it is created or renamed in an enabled chat.
+async fn topic_handler(db: &Db, msg: &Message) -> DbResult<()> {
+    match types::Topic::from(msg) {
+        Some(topic) if db.filter_chat_with_id(msg.chat.id).await?.is_some() => {
+            record_topic(db, topic).await.map(|_| ())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Stores what is new about `topic` and returns it as stored, `None` if it is stopped.
+async fn record_topic(db: &Db, topic: types::Topic) -> DbResult<Option<types::Topic>> {
+    let topic = match db.get_topic(topic.chat_id, topic.thread_id).await? {
+        Some(stored) if topic.name.is_none() || topic.name == stored.name => stored,
+        stored => {
+            let topic = types::Topic {
+                disabled: stored.is_some_and(|s| s.disabled),
+                ..topic
+            };
+            db.insert(std::slice::from_ref(&topic)).await?;
+            topic
+        }
+    };
+    Ok((!topic.disabled).then_some(topic))
+}
+
+/// Keeps the stored version of an edited message as a revision when its text changed, and
+/// carries its original text over to `message`.
+async fn record_revision(db: &Db, message: &mut types::Message) -> DbResult<()> {
+    let Some(stored) = db.get_message(&message.key).await? else {
+        return Ok(());
+    };
+    message.original = stored.original.clone();
+    if stored.text != message.text {
+        message.original.get_or_insert_with(|| stored.text.clone());
+        let replaced = message.edit_date.unwrap_or(message.date);
+        db.insert(&[stored.into_revision(replaced)]).await?;
+    }
+    Ok(())
+}
+
+/// Indexes a message of an enabled chat along with its sender and web pages, unless its
+/// forum topic is stopped.
+pub(crate) async fn index_message(db: &Db, config: &Config, msg: &Message) -> DbResult<()> {
+    if db.filter_chat_with_id(msg.chat.id).await?.is_none() {
+        log::debug!("{} not a enabled chat", &msg.chat.id);
+        return Ok(());
+    }
+
+    let mut message = types::Message::from(msg);
+    if let Some(topic) = types::Topic::from(msg) {
+        match record_topic(db, topic).await? {
+            Some(t) => message.topic = t.name,
+            None => {
+                log::debug!("topic {:?} of {} is stopped", msg.thread_id, msg.chat.id);
+                return Ok(());
+            }
+        }
+    }
+
+    if msg.edit_date().is_some() && config.keep_edit_history {
+        record_revision(db, &mut message).await?;
+    }
 
-    web_page_handler(msg).await;
+    db.insert(&types::Sender::from(msg)).await?;
+    db.insert(&[message]).await?;
 
+    // Sites can take a minute to answer, which must not hold up indexing other messages.
+    spawn_web_page_handler(db.clone(), msg.clone());
     Ok(())
 }
@@ -5,36 +5,14 @@ use tokio::{spawn, task::JoinHandle};
 use utf16string::{WStr, WString, LE};
 
 use crate::{
-    db::Db,
+    db::{Db, DbResult},
+    link_preview::link_previews,
     ogp::{read_open_graph, WebPage},
 };
 
-const WHITELISTED_DOMAINS: [&str; 9] = [
-    "fixupx.com",
-    "www.fixupx.com",
-    "fxtwitter.com",
-    "www.fxtwitter.com",
-    // "www.fxzhihu.com",
-    // "zhuanlan.fxzhihu.com",
-    "www.youtube.com",
-    "youtube.com",
-    "youtu.be",
-    "github.com",
-    "www.github.com",
-];
-
-const REDIRECT_DOMAINS: [(&str, &str); 4] = [
-    ("x.com", "fixupx.com"),
-    ("www.x.com", "www.fixupx.com"),
-    ("twitter.com", "fxtwitter.com"),
-    ("www.twitter.com", "www.fxtwitter.com"),
-    // ("www.zhihu.com", "www.fxzhihu.com"),
-    // ("zhuanlan.zhihu.com", "zhuanlan.fxzhihu.com"),
-];
-
-pub async fn web_page_handler(msg: Message) {
+pub async fn web_page_handler(db: &Db, msg: &Message) -> DbResult<()> {
     if msg.entities().is_none() {
-        return;
+        return Ok(());
     }
     let e = msg.entities().unwrap_or_default();
 
@@ -59,14 +37,30 @@ pub async fn web_page_handler(msg: Message) {
         .filter_map(|x| x.ok().flatten())
         .collect::<Vec<_>>();
 
-    Db::new()
-        .insert(
-            &web_pages
-                .iter()
-                .map(|p| crate::types::Message::from(&msg).set_web_page(p))
-                .collect::<Vec<_>>(),
-        )
-        .await;
+    db.insert(
+        &web_pages
+            .iter()
+            .map(|p| crate::types::Message::from(msg).set_web_page(p))
+            .collect::<Vec<_>>(),
+    )
+    .await
+}
+
+/// Indexes the web pages linked from `msg` in the background. Pages that cannot be read or
+/// stored are left out, the message itself is already indexed.
+pub fn spawn_web_page_handler(db: Db, msg: Message) {
+    if msg.entities().is_none() {
+        return;
+    }
+    spawn(async move {
+        if let Err(e) = web_page_handler(&db, &msg).await {
+            log::warn!(
+                "failed to index web pages of message {} in {}: {e}",
+                msg.id,
+                msg.chat.id
+            );
+        }
+    });
 }
 
 fn get_url_from_text(text: &String, offset: usize, length: usize) -> Option<Url> {
@@ -76,25 +70,9 @@ fn get_url_from_text(text: &String, offset: usize, length: usize) -> Option<Url>
     Url::parse(u16str.to_utf8().as_str()).ok()
 }
 
+/// The URL to read the web page of `url` from, if link previews are enabled for its domain.
 pub fn get_url_in_whitelist(url: &Url) -> Option<Url> {
-    if url.scheme() != "https" {
-        return None;
-    }
-
-    let mut url = url.clone();
-    url.set_host(Some(&get_domain_in_whitelist(url.domain()?)?))
-        .unwrap();
-    Some(url)
-}
-
-fn get_domain_in_whitelist(domain: &str) -> Option<String> {
-    if let Some(&d) = WHITELISTED_DOMAINS.iter().find(|&&x| x == domain) {
-        Some(d.to_string())
-    } else if let Some(&d) = REDIRECT_DOMAINS.iter().find(|&&x| x.0 == domain) {
-        Some(d.1.to_string())
-    } else {
-        None
-    }
+    link_previews().get_url(url)
 }
 
 #[cfg(test)]
@@ -132,8 +110,8 @@ mod web_page_handler_test {
 
     #[test]
     fn domain_whitelist_test() {
-        let domain = "www.fxtwitter.com";
-        assert_eq!(get_domain_in_whitelist(domain).unwrap(), domain.to_string());
+        let url = Url::parse("https://www.fxtwitter.com/mofu_sand").unwrap();
+        assert_eq!(get_url_in_whitelist(&url).unwrap(), url);
     }
 
     #[test]
@@ -1,6 +1,13 @@
+pub mod config;
+pub mod date;
 pub mod db;
 pub mod handlers;
+pub mod link_preview;
+pub mod normalize;
 pub mod ogp;
+pub mod prune;
+pub mod query;
+pub mod queue;
 pub mod types;
 
 pub static BOT_USERNAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
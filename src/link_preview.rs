@@ -0,0 +1,206 @@
+use std::sync::{Arc, RwLock};
+
+use reqwest::Url;
+use serde::Deserialize;
+
+/// Used when neither `LINK_PREVIEW_CONFIG` nor `LINK_PREVIEW_DOMAINS` is set.
+const DEFAULT_CONFIG: &str = include_str!("link_previews.json");
+
+static LINK_PREVIEWS: RwLock<Option<Arc<LinkPreviews>>> = RwLock::new(None);
+
+/// Which links get their web pages indexed, and how their URLs are rewritten before the pages
+/// are read, such as to a mirror that serves Open Graph tags.
+///
+/// They are read from the JSON file in `LINK_PREVIEW_CONFIG`, or else from
+/// `LINK_PREVIEW_DOMAINS`, a comma separated list of domains and `from=to` host swaps. A
+/// leading `*.` in a domain matches all of its subdomains.
+#[derive(Debug, Default, Deserialize)]
+pub struct LinkPreviews {
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default)]
+    rewrites: Vec<Rewrite>,
+}
+
+/// Rewrites links on the domains matching `from`, whose web pages are then read as well.
+#[derive(Debug, Deserialize)]
+struct Rewrite {
+    from: String,
+    /// New host, where `*` stands for the subdomain matched by `*.` in `from`.
+    #[serde(default)]
+    host: Option<String>,
+    /// New path, where `{path}` stands for the old one and `{1}`, `{2}`... for its segments.
+    #[serde(default)]
+    path: Option<String>,
+    /// Drops the query string, such as tracking parameters.
+    #[serde(default)]
+    strip_query: bool,
+}
+
+impl LinkPreviews {
+    pub fn from_env() -> Result<Self, String> {
+        if let Some(path) = std::env::var_os("LINK_PREVIEW_CONFIG") {
+            let path = path.to_string_lossy();
+            let config = std::fs::read_to_string(path.as_ref())
+                .map_err(|e| format!("failed to read {path}: {e}"))?;
+            serde_json::from_str(&config).map_err(|e| format!("invalid {path}: {e}"))
+        } else if let Ok(domains) = std::env::var("LINK_PREVIEW_DOMAINS") {
+            Ok(Self::from_list(&domains))
+        } else {
+            Ok(serde_json::from_str(DEFAULT_CONFIG).unwrap())
+        }
+    }
+
+    fn from_list(list: &str) -> Self {
+        let mut res = Self::default();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                Some((from, to)) => res.rewrites.push(Rewrite {
+                    from: from.trim().to_string(),
+                    host: Some(to.trim().to_string()),
+                    path: None,
+                    strip_query: false,
+                }),
+                None => res.domains.push(entry.to_string()),
+            }
+        }
+        res
+    }
+
+    /// The URL to read the web page of `url` from, `None` if its domain is not listed.
+    pub fn get_url(&self, url: &Url) -> Option<Url> {
+        if url.scheme() != "https" {
+            return None;
+        }
+
+        let domain = url.domain()?;
+        match self
+            .rewrites
+            .iter()
+            .find_map(|r| match_domain(&r.from, domain).map(|sub| (r, sub)))
+        {
+            Some((rewrite, sub)) => rewrite.apply(url, sub),
+            None => self
+                .domains
+                .iter()
+                .any(|d| match_domain(d, domain).is_some())
+                .then(|| url.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkPreviews {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} domains and {} rewrite rules",
+            self.domains.len(),
+            self.rewrites.len()
+        )
+    }
+}
+
+impl Rewrite {
+    fn apply(&self, url: &Url, sub: &str) -> Option<Url> {
+        let mut res = url.clone();
+        if let Some(host) = &self.host {
+            res.set_host(Some(&host.replace('*', sub))).ok()?;
+        }
+        if let Some(path) = &self.path {
+            let mut new_path = path.replace("{path}", url.path());
+            for (i, segment) in url.path_segments().into_iter().flatten().enumerate() {
+                new_path = new_path.replace(&format!("{{{}}}", i + 1), segment);
+            }
+            res.set_path(&new_path);
+        }
+        if self.strip_query {
+            res.set_query(None);
+        }
+        Some(res)
+    }
+}
+
+/// Whether `domain` matches `pattern`, along with the subdomain a leading `*.` stands for.
+fn match_domain<'a>(pattern: &str, domain: &'a str) -> Option<&'a str> {
+    match pattern.strip_prefix("*.") {
+        Some(base) => domain
+            .strip_suffix(base)?
+            .strip_suffix('.')
+            .filter(|sub| !sub.is_empty()),
+        None => (pattern == domain).then_some(""),
+    }
+}
+
+/// The link preview settings in use, read on first use.
+pub fn link_previews() -> Arc<LinkPreviews> {
+    if let Some(l) = LINK_PREVIEWS.read().unwrap().as_ref() {
+        return l.clone();
+    }
+    reload().unwrap_or_else(|e| {
+        log::error!("{e}, no web pages will be read");
+        let l = Arc::new(LinkPreviews::default());
+        *LINK_PREVIEWS.write().unwrap() = Some(l.clone());
+        l
+    })
+}
+
+/// Reads the link preview settings again. The ones in use are kept if the new ones are invalid.
+pub fn reload() -> Result<Arc<LinkPreviews>, String> {
+    let l = Arc::new(LinkPreviews::from_env()?);
+    *LINK_PREVIEWS.write().unwrap() = Some(l.clone());
+    Ok(l)
+}
+
+#[cfg(test)]
+mod link_preview_test {
+    use super::*;
+
+    fn get_url(config: &str, url: &str) -> Option<String> {
+        serde_json::from_str::<LinkPreviews>(config)
+            .unwrap()
+            .get_url(&Url::parse(url).unwrap())
+            .map(|u| u.to_string())
+    }
+
+    #[test]
+    fn wildcard_test() {
+        let config = r#"{"domains": ["*.youtube.com"]}"#;
+        assert_eq!(
+            get_url(config, "https://m.youtube.com/watch?v=1").as_deref(),
+            Some("https://m.youtube.com/watch?v=1")
+        );
+        assert_eq!(get_url(config, "https://youtube.com/watch?v=1"), None);
+        assert_eq!(get_url(config, "https://notyoutube.com/"), None);
+    }
+
+    #[test]
+    fn rewrite_test() {
+        let config = r#"{"rewrites": [
+            {"from": "*.zhihu.com", "host": "*.fxzhihu.com", "strip_query": true},
+            {"from": "youtube.com", "host": "www.youtube.com", "path": "/watch/{2}"}
+        ]}"#;
+        assert_eq!(
+            get_url(config, "https://zhuanlan.zhihu.com/p/1?utm_source=x").as_deref(),
+            Some("https://zhuanlan.fxzhihu.com/p/1")
+        );
+        assert_eq!(
+            get_url(config, "https://youtube.com/shorts/abc").as_deref(),
+            Some("https://www.youtube.com/watch/abc")
+        );
+    }
+
+    #[test]
+    fn from_list_test() {
+        let l = LinkPreviews::from_list("github.com, x.com=fixupx.com");
+        assert_eq!(
+            l.get_url(&Url::parse("https://x.com/a").unwrap())
+                .unwrap()
+                .as_str(),
+            "https://fixupx.com/a"
+        );
+        assert!(l
+            .get_url(&Url::parse("https://github.com/").unwrap())
+            .is_some());
+        assert_eq!(l.to_string(), "1 domains and 1 rewrite rules");
+    }
+}
@@ -0,0 +1,228 @@
+mod chinese;
+mod japanese;
+mod korean;
+mod pinyin;
+
+use std::{collections::HashSet, str::FromStr, sync::OnceLock};
+
+static NORMALIZER: OnceLock<Normalizer> = OnceLock::new();
+
+/// A way of writing the same word differently that search should see through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// Traditional Chinese characters are folded into Simplified ones.
+    Chinese,
+    /// Chinese is also indexed in pinyin, which ASCII queries match as well.
+    Pinyin,
+    /// Katakana is folded into hiragana, and kana is also indexed in romaji.
+    Japanese,
+    /// Hangul is also indexed as the unfinished syllables an input method shows while typing.
+    Korean,
+}
+
+/// Rewrites message text and queries alike, so that either spelling finds both.
+///
+/// The steps are set per deployment in `NORMALIZE`, as a comma separated list of `chinese`,
+/// `pinyin`, `japanese` and `korean`. Messages keep their original text, the normalized one
+/// is stored next to it, along with variants of it that are typed differently.
+#[derive(Debug, Default)]
+pub struct Normalizer(Vec<Step>);
+
+impl Normalizer {
+    pub fn from_env() -> Result<Self, String> {
+        std::env::var("NORMALIZE")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Step::from_str)
+            .collect::<Result<_, _>>()
+            .map(Normalizer)
+    }
+
+    pub fn new(steps: Vec<Step>) -> Self {
+        Normalizer(steps)
+    }
+
+    /// Rewrites `text` without changing its length in bytes, so offsets into it still hold.
+    pub fn normalize(&self, text: &str) -> String {
+        self.0
+            .iter()
+            .fold(text.to_string(), |text, step| match step {
+                Step::Chinese => chinese::to_simplified(&text),
+                Step::Japanese => japanese::to_hiragana(&text),
+                Step::Pinyin | Step::Korean => text,
+            })
+    }
+
+    /// Tokens to index next to `text`, for the steps that type it differently.
+    pub fn variants(&self, text: &str) -> Option<String> {
+        let mut seen = HashSet::new();
+        let tokens = self
+            .0
+            .iter()
+            .flat_map(|step| match step {
+                Step::Pinyin => pinyin::index(text),
+                Step::Japanese => japanese::index(text),
+                Step::Korean => korean::index(text),
+                Step::Chinese => vec![],
+            })
+            .filter(|t| seen.insert(t.clone()))
+            .collect::<Vec<_>>();
+        (!tokens.is_empty()).then(|| tokens.join(" "))
+    }
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chinese" => Ok(Step::Chinese),
+            "pinyin" => Ok(Step::Pinyin),
+            "japanese" => Ok(Step::Japanese),
+            "korean" => Ok(Step::Korean),
+            s => Err(format!("unknown NORMALIZE step {s}")),
+        }
+    }
+}
+
+/// Tokens of runs of syllables spelled in Latin letters: every syllable, and each one joined
+/// with the next.
+fn syllable_tokens<S: AsRef<str>>(runs: &[Vec<S>]) -> Vec<String> {
+    runs.iter()
+        .flat_map(|run| {
+            run.iter().map(|s| s.as_ref().to_string()).chain(
+                run.windows(2)
+                    .map(|w| w[0].as_ref().to_string() + w[1].as_ref()),
+            )
+        })
+        .collect()
+}
+
+/// The syllable pairs `syllable_tokens` stores for a word typed as `syllables`, when there are
+/// more than two of them and the word itself is not stored.
+fn syllable_pairs<S: AsRef<str>>(syllables: &[S]) -> Option<Vec<String>> {
+    (syllables.len() > 2).then(|| {
+        syllables
+            .windows(2)
+            .map(|w| w[0].as_ref().to_string() + w[1].as_ref())
+            .collect()
+    })
+}
+
+/// Splits `word` into as few syllables as possible, none longer than `max_len` bytes.
+fn segment(word: &str, max_len: usize, is_syllable: impl Fn(&str) -> bool) -> Option<Vec<&str>> {
+    // best[i] is the shortest split of word[..i], as its length and the start of its last
+    // syllable.
+    let mut best: Vec<Option<(usize, usize)>> = vec![None; word.len() + 1];
+    best[0] = Some((0, 0));
+    for end in 1..=word.len() {
+        best[end] = (end.saturating_sub(max_len)..end)
+            .filter(|&start| word.is_char_boundary(start) && is_syllable(&word[start..end]))
+            .filter_map(|start| best[start].map(|(count, _)| (count + 1, start)))
+            .min();
+    }
+
+    let mut res = vec![];
+    let mut end = word.len();
+    while end > 0 {
+        let (_, start) = best[end]?;
+        res.push(&word[start..end]);
+        end = start;
+    }
+    res.reverse();
+    Some(res)
+}
+
+/// The normalizer set up in `NORMALIZE`. A broken setting is logged once and turns
+/// normalization off.
+pub fn normalizer() -> &'static Normalizer {
+    NORMALIZER.get_or_init(|| {
+        Normalizer::from_env().unwrap_or_else(|e| {
+            log::error!("{e}, text will not be normalized");
+            Normalizer::default()
+        })
+    })
+}
+
+/// Normalizes `text` with the deployment's normalizer, `None` if that changes nothing.
+pub fn normalize(text: &str) -> Option<String> {
+    Some(normalizer().normalize(text)).filter(|n| n != text)
+}
+
+/// Tokens to index next to `text`, for the variants the deployment enables.
+pub fn variants(text: &str) -> Option<String> {
+    normalizer().variants(text)
+}
+
+/// Sets of variant tokens a query word typed in pinyin or romaji may stand for, any of which
+/// matches, when they differ from the word itself.
+pub fn variant_tokens(word: &str) -> Vec<Vec<String>> {
+    if !word.chars().all(|c| c.is_ascii_alphabetic()) {
+        return vec![];
+    }
+    let word = word.to_ascii_lowercase();
+    [pinyin::split(&word), japanese::split(&word)]
+        .into_iter()
+        .flatten()
+        .filter(|tokens| tokens != std::slice::from_ref(&word))
+        .collect()
+}
+
+#[cfg(test)]
+mod normalize_test {
+    use super::*;
+
+    #[test]
+    fn chinese_test() {
+        let n = Normalizer::new(vec![Step::Chinese]);
+        assert_eq!(n.normalize("經濟發展, Rust 1.0"), "经济发展, Rust 1.0");
+        assert_eq!(n.normalize("经济发展"), "经济发展");
+        assert_eq!(Normalizer::default().normalize("發展"), "發展");
+    }
+
+    #[test]
+    fn pinyin_test() {
+        let n = Normalizer::new(vec![Step::Chinese, Step::Pinyin]);
+        assert_eq!(n.normalize("發展"), "发展");
+        assert_eq!(n.variants("發展").as_deref(), Some("fa zhan fazhan fz"));
+        assert_eq!(Normalizer::default().variants("发展"), None);
+    }
+
+    #[test]
+    fn step_test() {
+        assert_eq!("chinese".parse::<Step>(), Ok(Step::Chinese));
+        assert_eq!("pinyin".parse::<Step>(), Ok(Step::Pinyin));
+        assert_eq!("korean".parse::<Step>(), Ok(Step::Korean));
+        assert!("klingon".parse::<Step>().is_err());
+    }
+
+    #[test]
+    fn japanese_test() {
+        let n = Normalizer::new(vec![Step::Japanese]);
+        assert_eq!(n.normalize("ラーメン食べたい"), "らーめん食べたい");
+        assert_eq!(n.variants("ラーメン").as_deref(), Some("ra me n rame men"));
+    }
+
+    #[test]
+    fn segment_test() {
+        let syllables = ["a", "ab", "b", "c"];
+        let is_syllable = |s: &str| syllables.contains(&s);
+        assert_eq!(segment("abc", 2, is_syllable), Some(vec!["ab", "c"]));
+        assert_eq!(segment("abd", 2, is_syllable), None);
+    }
+
+    #[test]
+    fn variant_tokens_test() {
+        assert!(variant_tokens("Arigatou").contains(&vec![
+            "ari".to_string(),
+            "riga".into(),
+            "gato".into(),
+            "tou".into()
+        ]));
+        assert_eq!(variant_tokens("sushi"), Vec::<Vec<String>>::new());
+        assert_eq!(variant_tokens("susi"), vec![vec!["sushi".to_string()]]);
+        assert_eq!(variant_tokens("发展"), Vec::<Vec<String>>::new());
+    }
+}
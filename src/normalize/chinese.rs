@@ -0,0 +1,19 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+/// Traditional to Simplified pairs, one per line. Every pair is encoded in the same number of
+/// bytes, so converting keeps byte offsets of the text intact.
+static TRADITIONAL_TO_SIMPLIFIED: LazyLock<HashMap<char, char>> = LazyLock::new(|| {
+    include_str!("t2s.txt")
+        .lines()
+        .filter_map(|l| {
+            let mut chars = l.chars();
+            Some((chars.next()?, chars.next()?))
+        })
+        .collect()
+});
+
+pub(super) fn to_simplified(text: &str) -> String {
+    text.chars()
+        .map(|c| *TRADITIONAL_TO_SIMPLIFIED.get(&c).unwrap_or(&c))
+        .collect()
+}
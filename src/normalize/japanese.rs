@@ -0,0 +1,255 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+/// Hiragana, alone or with a small kana, with their Hepburn romaji and other spellings in use.
+const KANA: &[(&str, &str, &[&str])] = &[
+    ("あ", "a", &[]),
+    ("い", "i", &[]),
+    ("う", "u", &[]),
+    ("え", "e", &[]),
+    ("お", "o", &[]),
+    ("か", "ka", &[]),
+    ("き", "ki", &[]),
+    ("く", "ku", &[]),
+    ("け", "ke", &[]),
+    ("こ", "ko", &[]),
+    ("さ", "sa", &[]),
+    ("し", "shi", &["si"]),
+    ("す", "su", &[]),
+    ("せ", "se", &[]),
+    ("そ", "so", &[]),
+    ("た", "ta", &[]),
+    ("ち", "chi", &["ti"]),
+    ("つ", "tsu", &["tu"]),
+    ("て", "te", &[]),
+    ("と", "to", &[]),
+    ("な", "na", &[]),
+    ("に", "ni", &[]),
+    ("ぬ", "nu", &[]),
+    ("ね", "ne", &[]),
+    ("の", "no", &[]),
+    ("は", "ha", &[]),
+    ("ひ", "hi", &[]),
+    ("ふ", "fu", &["hu"]),
+    ("へ", "he", &[]),
+    ("ほ", "ho", &[]),
+    ("ま", "ma", &[]),
+    ("み", "mi", &[]),
+    ("む", "mu", &[]),
+    ("め", "me", &[]),
+    ("も", "mo", &[]),
+    ("や", "ya", &[]),
+    ("ゆ", "yu", &[]),
+    ("よ", "yo", &[]),
+    ("ら", "ra", &[]),
+    ("り", "ri", &[]),
+    ("る", "ru", &[]),
+    ("れ", "re", &[]),
+    ("ろ", "ro", &[]),
+    ("わ", "wa", &[]),
+    ("ゐ", "i", &[]),
+    ("ゑ", "e", &[]),
+    ("を", "o", &["wo"]),
+    ("ん", "n", &["nn"]),
+    ("が", "ga", &[]),
+    ("ぎ", "gi", &[]),
+    ("ぐ", "gu", &[]),
+    ("げ", "ge", &[]),
+    ("ご", "go", &[]),
+    ("ざ", "za", &[]),
+    ("じ", "ji", &["zi"]),
+    ("ず", "zu", &[]),
+    ("ぜ", "ze", &[]),
+    ("ぞ", "zo", &[]),
+    ("だ", "da", &[]),
+    ("ぢ", "ji", &["di"]),
+    ("づ", "zu", &["du"]),
+    ("で", "de", &[]),
+    ("ど", "do", &[]),
+    ("ば", "ba", &[]),
+    ("び", "bi", &[]),
+    ("ぶ", "bu", &[]),
+    ("べ", "be", &[]),
+    ("ぼ", "bo", &[]),
+    ("ぱ", "pa", &[]),
+    ("ぴ", "pi", &[]),
+    ("ぷ", "pu", &[]),
+    ("ぺ", "pe", &[]),
+    ("ぽ", "po", &[]),
+    ("ゔ", "vu", &[]),
+    ("ぁ", "a", &[]),
+    ("ぃ", "i", &[]),
+    ("ぅ", "u", &[]),
+    ("ぇ", "e", &[]),
+    ("ぉ", "o", &[]),
+    ("ゃ", "ya", &[]),
+    ("ゅ", "yu", &[]),
+    ("ょ", "yo", &[]),
+    ("ゎ", "wa", &[]),
+    ("きゃ", "kya", &[]),
+    ("きゅ", "kyu", &[]),
+    ("きょ", "kyo", &[]),
+    ("しゃ", "sha", &["sya"]),
+    ("しゅ", "shu", &["syu"]),
+    ("しょ", "sho", &["syo"]),
+    ("しぇ", "she", &["sye"]),
+    ("ちゃ", "cha", &["tya", "cya"]),
+    ("ちゅ", "chu", &["tyu", "cyu"]),
+    ("ちょ", "cho", &["tyo", "cyo"]),
+    ("ちぇ", "che", &["tye", "cye"]),
+    ("にゃ", "nya", &[]),
+    ("にゅ", "nyu", &[]),
+    ("にょ", "nyo", &[]),
+    ("ひゃ", "hya", &[]),
+    ("ひゅ", "hyu", &[]),
+    ("ひょ", "hyo", &[]),
+    ("みゃ", "mya", &[]),
+    ("みゅ", "myu", &[]),
+    ("みょ", "myo", &[]),
+    ("りゃ", "rya", &[]),
+    ("りゅ", "ryu", &[]),
+    ("りょ", "ryo", &[]),
+    ("ぎゃ", "gya", &[]),
+    ("ぎゅ", "gyu", &[]),
+    ("ぎょ", "gyo", &[]),
+    ("じゃ", "ja", &["zya", "jya"]),
+    ("じゅ", "ju", &["zyu", "jyu"]),
+    ("じょ", "jo", &["zyo", "jyo"]),
+    ("じぇ", "je", &["zye", "jye"]),
+    ("ぢゃ", "ja", &["dya"]),
+    ("ぢゅ", "ju", &["dyu"]),
+    ("ぢょ", "jo", &["dyo"]),
+    ("びゃ", "bya", &[]),
+    ("びゅ", "byu", &[]),
+    ("びょ", "byo", &[]),
+    ("ぴゃ", "pya", &[]),
+    ("ぴゅ", "pyu", &[]),
+    ("ぴょ", "pyo", &[]),
+    ("ふぁ", "fa", &[]),
+    ("ふぃ", "fi", &[]),
+    ("ふぇ", "fe", &[]),
+    ("ふぉ", "fo", &[]),
+];
+
+/// Longest spelling accepted for a kana, such as `tchya`.
+const MAX_SPELLING_LEN: usize = 5;
+
+static ROMAJI: LazyLock<HashMap<&'static str, &'static str>> = LazyLock::new(|| {
+    KANA.iter()
+        .map(|&(kana, romaji, _)| (kana, romaji))
+        .collect()
+});
+
+/// Every accepted spelling of a kana, doubled consonants for a preceding small tsu included,
+/// to the Hepburn one `index` stores.
+static SPELLINGS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
+    let mut res = HashMap::new();
+    for &(_, romaji, others) in KANA {
+        for spelling in std::iter::once(&romaji).chain(others) {
+            res.insert(spelling.to_string(), romaji.to_string());
+            if spelling.starts_with(|c| !"aeioun".contains(c)) {
+                res.insert(format!("{}{spelling}", &spelling[..1]), geminate(romaji));
+                if spelling.starts_with("ch") {
+                    res.insert(format!("t{spelling}"), geminate(romaji));
+                }
+            }
+        }
+    }
+    res
+});
+
+/// Spells a kana after a small tsu, which doubles its consonant.
+fn geminate(romaji: &str) -> String {
+    match romaji.starts_with("ch") {
+        true => format!("t{romaji}"),
+        false => format!("{}{romaji}", &romaji[..1]),
+    }
+}
+
+/// Folds katakana into hiragana, both three bytes long in UTF-8.
+pub(super) fn to_hiragana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'ァ'..='ヶ' | 'ヽ' | 'ヾ' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            c => c,
+        })
+        .collect()
+}
+
+/// Romaji tokens of the kana in `text`: every kana spelled in Hepburn romaji and each joined
+/// with the next, so that `sushi` and `ramen` find すし and ラーメン.
+pub(super) fn index(text: &str) -> Vec<String> {
+    let chars = to_hiragana(text).chars().collect::<Vec<_>>();
+    let mut runs: Vec<Vec<String>> = vec![vec![]];
+    let (mut i, mut small_tsu) = (0, false);
+    while i < chars.len() {
+        let pair = chars[i..chars.len().min(i + 2)].iter().collect::<String>();
+        let (romaji, len) = match (
+            ROMAJI.get(pair.as_str()),
+            ROMAJI.get(&*chars[i].to_string()),
+        ) {
+            (Some(r), _) if pair.chars().count() == 2 => (Some(*r), 2),
+            (_, r) => (r.copied(), 1),
+        };
+        match (chars[i], romaji) {
+            ('っ', _) => small_tsu = true,
+            ('ー', _) => {}
+            (_, Some(r)) => {
+                let run = runs.last_mut().unwrap();
+                match small_tsu && !r.starts_with(|c| "aeioun".contains(c)) {
+                    true => run.push(geminate(r)),
+                    false => run.push(r.to_string()),
+                }
+                small_tsu = false;
+            }
+            (_, None) => {
+                small_tsu = false;
+                if !runs.last().unwrap().is_empty() {
+                    runs.push(vec![]);
+                }
+            }
+        }
+        i += len;
+    }
+    super::syllable_tokens(&runs)
+}
+
+/// Splits a lowercase word typed in romaji into the tokens `index` stores, in Hepburn
+/// spelling: `arigatou` into `ari`, `riga`, `gato` and `tou`, `susi` into `sushi`.
+pub(super) fn split(word: &str) -> Option<Vec<String>> {
+    let spellings = super::segment(word, MAX_SPELLING_LEN, |s| SPELLINGS.contains_key(s))?
+        .into_iter()
+        .map(|s| SPELLINGS[s].as_str())
+        .collect::<Vec<_>>();
+    super::syllable_pairs(&spellings).or_else(|| Some(vec![spellings.concat()]))
+}
+
+#[cfg(test)]
+mod japanese_test {
+    use super::*;
+
+    #[test]
+    fn to_hiragana_test() {
+        let text = "カタカナとひらがな, ヴ";
+        assert_eq!(to_hiragana(text), "かたかなとひらがな, ゔ");
+        assert_eq!(to_hiragana(text).len(), text.len());
+    }
+
+    #[test]
+    fn index_test() {
+        assert_eq!(
+            index("きって、チョコ"),
+            vec!["ki", "tte", "kitte", "cho", "ko", "choko"]
+        );
+        assert!(index("漢字").is_empty());
+    }
+
+    #[test]
+    fn split_test() {
+        assert_eq!(split("kitte"), Some(vec!["kitte".to_string()]));
+        assert_eq!(split("matcha"), Some(vec!["matcha".to_string()]));
+        assert_eq!(
+            split("tyokoreto"),
+            Some(vec!["choko".into(), "kore".into(), "reto".into()])
+        );
+    }
+}
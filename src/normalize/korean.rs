@@ -0,0 +1,117 @@
+const FIRST_SYLLABLE: u32 = 0xAC00;
+const LAST_SYLLABLE: u32 = 0xD7A3;
+const VOWELS: u32 = 21;
+const FINALS: u32 = 28;
+
+/// Initial consonants as typed on their own, in compatibility jamo.
+const INITIALS: [char; 19] = [
+    'ㄱ', 'ㄲ', 'ㄴ', 'ㄷ', 'ㄸ', 'ㄹ', 'ㅁ', 'ㅂ', 'ㅃ', 'ㅅ', 'ㅆ', 'ㅇ', 'ㅈ', 'ㅉ', 'ㅊ', 'ㅋ',
+    'ㅌ', 'ㅍ', 'ㅎ',
+];
+
+/// The final consonant each initial one is shown as when typed right after a vowel, 0 for
+/// those that cannot end a syllable.
+const INITIAL_AS_FINAL: [u32; 19] = [
+    1, 2, 4, 7, 0, 8, 16, 17, 0, 19, 20, 21, 22, 0, 23, 24, 25, 26, 27,
+];
+
+fn decompose(c: char) -> Option<(u32, u32, u32)> {
+    let s = (c as u32).checked_sub(FIRST_SYLLABLE)?;
+    (c as u32 <= LAST_SYLLABLE).then_some((s / (VOWELS * FINALS), s / FINALS % VOWELS, s % FINALS))
+}
+
+fn compose(initial: u32, vowel: u32, last: u32) -> char {
+    char::from_u32(FIRST_SYLLABLE + (initial * VOWELS + vowel) * FINALS + last).unwrap()
+}
+
+/// The part typed first of a compound vowel, such as ㅗ of ㅘ.
+fn first_vowel(vowel: u32) -> u32 {
+    match vowel {
+        9..=11 => 8,
+        14..=16 => 13,
+        19 => 18,
+        v => v,
+    }
+}
+
+/// The part typed first of a compound final, such as ㄹ of ㄺ.
+fn first_final(last: u32) -> u32 {
+    match last {
+        3 => 1,
+        5 | 6 => 4,
+        9..=15 => 8,
+        18 => 17,
+        t => t,
+    }
+}
+
+/// What an input method shows while `c` is being typed, before it is complete.
+fn unfinished(c: char) -> Vec<char> {
+    let Some((initial, vowel, last)) = decompose(c) else {
+        return vec![];
+    };
+    let mut res = vec![INITIALS[initial as usize]];
+    if first_vowel(vowel) != vowel {
+        res.push(compose(initial, first_vowel(vowel), 0));
+    }
+    if last != 0 {
+        res.push(compose(initial, vowel, 0));
+        if first_final(last) != last {
+            res.push(compose(initial, vowel, first_final(last)));
+        }
+    }
+    res
+}
+
+/// Unfinished forms of the Hangul in `text`, alone and after the syllable before them, so
+/// that a query typed up to `한구` or `한ㄱ` already finds 한국. The initial consonant of a
+/// syllable shows up as the final one of the syllable before until its vowel is typed,
+/// which makes `한` find 하나 too.
+pub(super) fn index(text: &str) -> Vec<String> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut res = vec![];
+    for (i, &c) in chars.iter().enumerate() {
+        let Some((initial, vowel, last)) = decompose(c) else {
+            continue;
+        };
+        let mut forms = unfinished(c);
+        if let Some((next, _, _)) = chars.get(i + 1).and_then(|&n| decompose(n)) {
+            let carried = INITIAL_AS_FINAL[next as usize];
+            if last == 0 && carried != 0 {
+                forms.push(compose(initial, vowel, carried));
+            }
+        }
+        let before = i
+            .checked_sub(1)
+            .map(|j| chars[j])
+            .filter(|&b| decompose(b).is_some());
+        for form in forms {
+            res.push(form.to_string());
+            res.extend(before.map(|b| format!("{b}{form}")));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod korean_test {
+    use super::*;
+
+    #[test]
+    fn unfinished_test() {
+        assert_eq!(unfinished('한'), vec!['ㅎ', '하']);
+        assert_eq!(unfinished('닭'), vec!['ㄷ', '다', '달']);
+        assert_eq!(unfinished('과'), vec!['ㄱ', '고']);
+        assert!(unfinished('a').is_empty());
+    }
+
+    #[test]
+    fn index_test() {
+        let tokens = index("한국");
+        for t in ["ㄱ", "구", "한ㄱ", "한구"] {
+            assert!(tokens.contains(&t.to_string()), "{t}");
+        }
+        assert!(index("하나").contains(&"한".to_string()));
+        assert!(index("hello").is_empty());
+    }
+}
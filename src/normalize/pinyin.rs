@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+/// Longest syllable in the table, such as `zhuang`.
+const MAX_SYLLABLE_LEN: usize = 6;
+
+/// Toneless syllables followed by the characters read that way, one syllable per line.
+/// Characters with several readings are listed under their most common one.
+static READINGS: LazyLock<HashMap<char, &'static str>> = LazyLock::new(|| {
+    include_str!("pinyin.txt")
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .flat_map(|(syllable, chars)| chars.chars().map(move |c| (c, syllable)))
+        .collect()
+});
+
+static SYLLABLES: LazyLock<HashSet<&'static str>> =
+    LazyLock::new(|| READINGS.values().copied().collect());
+
+fn initial(syllable: &str) -> &str {
+    &syllable[..1]
+}
+
+/// Pinyin tokens of the Chinese characters in `text`: the syllable tokens, plus the initials
+/// of syllable pairs, so that `fa`, `fazhan` and `fz` all find 发展.
+pub(super) fn index(text: &str) -> Vec<String> {
+    let mut runs: Vec<Vec<&str>> = vec![vec![]];
+    for c in text.chars() {
+        match READINGS.get(&c) {
+            Some(s) => runs.last_mut().unwrap().push(s),
+            None if runs.last().unwrap().is_empty() => {}
+            None => runs.push(vec![]),
+        }
+    }
+
+    let initials = runs.iter().flat_map(|run| {
+        run.windows(2)
+            .map(|w| format!("{}{}", initial(w[0]), initial(w[1])))
+    });
+    super::syllable_tokens(&runs)
+        .into_iter()
+        .chain(initials)
+        .collect()
+}
+
+/// Splits a lowercase word typed in pinyin into the tokens `index` stores: `jingjifazhan`
+/// into `jingji`, `jifa` and `fazhan`, `jjfz` into `jj`, `jf` and `fz`.
+pub(super) fn split(word: &str) -> Option<Vec<String>> {
+    match super::segment(word, MAX_SYLLABLE_LEN, |s| SYLLABLES.contains(s)) {
+        Some(syllables) => super::syllable_pairs(&syllables),
+        None => {
+            super::syllable_pairs(&(0..word.len()).map(|i| &word[i..i + 1]).collect::<Vec<_>>())
+        }
+    }
+}
+
+#[cfg(test)]
+mod pinyin_test {
+    use super::*;
+
+    #[test]
+    fn index_test() {
+        assert_eq!(
+            index("发展, 很快"),
+            vec!["fa", "zhan", "fazhan", "hen", "kuai", "henkuai", "fz", "hk"]
+        );
+        assert!(index("Rust 1.0").is_empty());
+    }
+
+    #[test]
+    fn split_test() {
+        assert_eq!(
+            split("jingjifazhan"),
+            Some(vec!["jingji".into(), "jifa".into(), "fazhan".into()])
+        );
+        assert_eq!(
+            split("jjfz"),
+            Some(vec!["jj".into(), "jf".into(), "fz".into()])
+        );
+        assert_eq!(split("fazhan"), None);
+        assert_eq!(split("fz"), None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+use teloxide::{
+    prelude::*,
+    requests::{JsonRequest, Output, Payload, Request},
+    types::{MessageId, True},
+    ApiError, RequestError,
+};
+
+use crate::{
+    db::{Db, GET_LIMIT},
+    handlers::HandlerResult,
+};
+
+/// `copyMessages` and `deleteMessages` accept at most this many ids per call.
+const PROBE_BATCH_LIMIT: usize = 100;
+const PROBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Finds indexed messages that were deleted from Telegram and removes them from the index.
+///
+/// Bots get no updates for deletions, so stored messages are probed by copying them to a
+/// scratch chat set in `PRUNE_CHAT_ID`, then deleting the copies right away. Whatever cannot
+/// be found any more is gone.
+pub struct Pruner {
+    scratch_chat: Option<ChatId>,
+    delay: Duration,
+}
+
+// teloxide sends `message_ids` as `{"message_id": n}` objects, which Telegram rejects, so
+// these two are spelled out with plain integers.
+#[derive(Serialize)]
+struct CopyMessages {
+    chat_id: ChatId,
+    from_chat_id: ChatId,
+    message_ids: Vec<i32>,
+    disable_notification: bool,
+}
+
+#[derive(Serialize)]
+struct DeleteMessages {
+    chat_id: ChatId,
+    message_ids: Vec<i32>,
+}
+
+impl Pruner {
+    pub fn from_env() -> Result<Self, std::num::ParseIntError> {
+        Ok(Self::new(
+            std::env::var("PRUNE_CHAT_ID")
+                .ok()
+                .map(|c| c.parse().map(ChatId))
+                .transpose()?,
+            PROBE_DELAY,
+        ))
+    }
+
+    /// `delay` is waited between probes to stay clear of flood limits.
+    pub fn new(scratch_chat: Option<ChatId>, delay: Duration) -> Self {
+        Pruner {
+            scratch_chat,
+            delay,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.scratch_chat.is_some()
+    }
+
+    /// Probes every stored message of `chat_id`, returning how many were removed.
+    pub async fn prune_chat(&self, bot: &Bot, db: &Db, chat_id: ChatId) -> HandlerResult<usize> {
+        let Some(scratch_chat) = self.scratch_chat else {
+            return Ok(0);
+        };
+
+        // Messages with web pages are stored once per page, all of them share the message id.
+        let mut keys = BTreeMap::<i32, Vec<String>>::new();
+        let mut offset = 0;
+        loop {
+            let page = db.get_messages(Some(chat_id), offset, GET_LIMIT).await?;
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len();
+            for m in page {
+                keys.entry(m.id).or_default().push(m.key);
+            }
+        }
+
+        let ids = keys.keys().copied().collect::<Vec<_>>();
+        let (mut pruned, mut deleted) = (0, vec![]);
+        for batch in ids.chunks(PROBE_BATCH_LIMIT) {
+            match retry(|| {
+                JsonRequest::new(
+                    bot.clone(),
+                    CopyMessages {
+                        chat_id: scratch_chat,
+                        from_chat_id: chat_id,
+                        message_ids: batch.to_vec(),
+                        disable_notification: true,
+                    },
+                )
+            })
+            .await
+            {
+                Ok(copies) => {
+                    self.clean_up(bot, scratch_chat, copies.iter().copied())
+                        .await?;
+                    if copies.len() == batch.len() {
+                        continue;
+                    }
+                }
+                // One message that cannot be copied, like one with protected content, fails
+                // the whole batch.
+                Err(RequestError::Api(e)) => {
+                    log::debug!("cannot probe a batch of messages in {chat_id} at once: {e}")
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            // Some of them are missing or cannot be copied, find out which ones.
+            for id in batch {
+                match retry(|| {
+                    bot.copy_message(scratch_chat, chat_id, MessageId(*id))
+                        .disable_notification(true)
+                })
+                .await
+                {
+                    Ok(copy) => self.clean_up(bot, scratch_chat, [copy]).await?,
+                    Err(RequestError::Api(ApiError::MessageToCopyNotFound)) => {
+                        pruned += 1;
+                        deleted.extend(keys.remove(id).unwrap_or_default());
+                    }
+                    Err(RequestError::Api(e)) => {
+                        log::debug!("cannot probe message {id} in {chat_id}: {e}")
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        if !deleted.is_empty() {
+            db.delete_messages(&deleted).await?;
+        }
+        log::info!("Pruned {pruned} deleted messages in {chat_id}.");
+        Ok(pruned)
+    }
+
+    async fn clean_up(
+        &self,
+        bot: &Bot,
+        scratch_chat: ChatId,
+        copies: impl IntoIterator<Item = MessageId>,
+    ) -> ResponseResult<()> {
+        let copies = copies.into_iter().collect::<Vec<_>>();
+        if !copies.is_empty() {
+            retry(|| {
+                JsonRequest::new(
+                    bot.clone(),
+                    DeleteMessages {
+                        chat_id: scratch_chat,
+                        message_ids: copies.iter().map(|m| m.0).collect(),
+                    },
+                )
+            })
+            .await?;
+        }
+        tokio::time::sleep(self.delay).await;
+        Ok(())
+    }
+}
+
+/// Sends a request again for as long as Telegram asks to slow down.
+async fn retry<R>(request: impl Fn() -> R) -> ResponseResult<Output<R>>
+where
+    R: Request<Err = RequestError>,
+{
+    loop {
+        match request().send().await {
+            Err(RequestError::RetryAfter(d)) => tokio::time::sleep(d.duration()).await,
+            r => return r,
+        }
+    }
+}
+
+impl Payload for CopyMessages {
+    type Output = Vec<MessageId>;
+
+    const NAME: &'static str = "CopyMessages";
+}
+
+impl Payload for DeleteMessages {
+    type Output = True;
+
+    const NAME: &'static str = "DeleteMessages";
+}
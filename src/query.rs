@@ -0,0 +1,291 @@
+/// Most alternatives a query may expand to, each of them costs a search on some backends.
+const MAX_BRANCHES: usize = 8;
+
+/// Keywords of an inline query, with the operators users can combine them with:
+/// `"quoted phrases"`, `-excluded` words, `a OR b` and `(groups)`. Words next to each other
+/// must all match.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Term(String),
+    Phrase(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Minus,
+    Or,
+    Open,
+    Close,
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let tokens = lex(s)?;
+        let mut pos = 0;
+        let expr = parse_or(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            None => {}
+            Some(Token::Close) => return Err("unmatched )".to_string()),
+            Some(_) => unreachable!("parse_or stops at ) or the end only"),
+        }
+        if expr.branches().len() > MAX_BRANCHES {
+            return Err(format!(
+                "too many alternatives, at most {MAX_BRANCHES} are allowed"
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// Whether this is only words and phrases that must all match, which every backend
+    /// understands as is.
+    pub fn is_simple(&self) -> bool {
+        match self {
+            Expr::Term(_) | Expr::Phrase(_) => true,
+            Expr::And(v) => v.iter().all(Expr::is_simple),
+            Expr::Not(_) | Expr::Or(_) => false,
+        }
+    }
+
+    /// Words and phrases a match may contain, leaving out excluded ones.
+    pub fn terms(&self) -> Vec<&str> {
+        match self {
+            Expr::Term(t) | Expr::Phrase(t) => vec![t],
+            Expr::Not(_) => vec![],
+            Expr::And(v) | Expr::Or(v) => v.iter().flat_map(Expr::terms).collect(),
+        }
+    }
+
+    /// Expands `OR` into alternatives of words and phrases that must all match. Excluded
+    /// ones are left out, so matches of the alternatives still need to be checked against
+    /// the whole expression.
+    pub fn branches(&self) -> Vec<Vec<&Expr>> {
+        match self {
+            Expr::Term(_) | Expr::Phrase(_) => vec![vec![self]],
+            Expr::Not(_) => vec![vec![]],
+            Expr::And(v) => v.iter().fold(vec![vec![]], |acc, e| {
+                let branches = e.branches();
+                acc.iter()
+                    .flat_map(|a| {
+                        branches
+                            .iter()
+                            .map(|b| a.iter().chain(b).copied().collect::<Vec<_>>())
+                    })
+                    .collect()
+            }),
+            Expr::Or(v) => v.iter().flat_map(Expr::branches).collect(),
+        }
+    }
+}
+
+/// Splits `s` on whitespace outside of quotes, the way `lex` reads it, so that a quoted
+/// phrase stays in one piece. Each word comes with whether any part of it is quoted. An
+/// unclosed quote runs to the end.
+pub fn split_words(s: &str) -> Vec<(&str, bool)> {
+    let mut res = vec![];
+    let (mut start, mut quoted, mut in_quote) = (None, false, false);
+    for (i, c) in s.char_indices() {
+        if c.is_whitespace() && !in_quote {
+            if let Some(start) = start.take() {
+                res.push((&s[start..i], quoted));
+            }
+            quoted = false;
+            continue;
+        }
+        if c == '"' {
+            in_quote = !in_quote;
+            quoted = true;
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        res.push((&s[start..], quoted));
+    }
+    res
+}
+
+fn lex(s: &str) -> Result<Vec<Token>, String> {
+    let mut res = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let (mut phrase, mut closed) = (String::new(), false);
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                    phrase.push(c);
+                }
+                if !closed {
+                    return Err("unclosed quote".to_string());
+                }
+                let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+                if phrase.is_empty() {
+                    return Err("empty phrase".to_string());
+                }
+                res.push(Token::Phrase(phrase));
+            }
+            '(' | ')' => {
+                chars.next();
+                res.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '-' => {
+                chars.next();
+                match chars.peek() {
+                    Some(&c) if !c.is_whitespace() && c != ')' => res.push(Token::Minus),
+                    _ => return Err("nothing to exclude after -".to_string()),
+                }
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '"' | '(' | ')') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                res.push(match word.as_str() {
+                    "OR" => Token::Or,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut alternatives = vec![parse_and(tokens, pos)?];
+    while tokens.get(*pos) == Some(&Token::Or) {
+        *pos += 1;
+        alternatives.push(parse_and(tokens, pos)?);
+    }
+    if alternatives.len() > 1 && alternatives.contains(&Expr::And(vec![])) {
+        return Err("OR needs keywords on both sides".to_string());
+    }
+    Ok(match alternatives.len() {
+        1 => alternatives.pop().unwrap(),
+        _ => Expr::Or(alternatives),
+    })
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let mut all = vec![];
+    while let Some(t) = tokens.get(*pos) {
+        match t {
+            Token::Or | Token::Close => break,
+            Token::Minus => {
+                *pos += 1;
+                all.push(Expr::Not(Box::new(parse_primary(tokens, pos)?)));
+            }
+            _ => all.push(parse_primary(tokens, pos)?),
+        }
+    }
+    Ok(match all.len() {
+        1 => all.pop().unwrap(),
+        _ => Expr::And(all),
+    })
+}
+
+fn parse_primary(tokens: &[Token], pos: &mut usize) -> Result<Expr, String> {
+    let t = tokens.get(*pos);
+    *pos += 1;
+    match t {
+        Some(Token::Word(w)) => Ok(Expr::Term(w.clone())),
+        Some(Token::Phrase(p)) => Ok(Expr::Phrase(p.clone())),
+        Some(Token::Open) => {
+            let expr = parse_or(tokens, pos)?;
+            if tokens.get(*pos) != Some(&Token::Close) {
+                return Err("unmatched (".to_string());
+            }
+            *pos += 1;
+            if expr == Expr::And(vec![]) {
+                return Err("empty parentheses".to_string());
+            }
+            Ok(expr)
+        }
+        Some(Token::Or) => Err("OR needs keywords on both sides".to_string()),
+        Some(Token::Close) => Err("unmatched )".to_string()),
+        Some(Token::Minus) | None => Err("nothing to exclude after -".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod query_test {
+    use super::*;
+
+    fn term(s: &str) -> Expr {
+        Expr::Term(s.to_string())
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Expr::parse("").unwrap(), Expr::And(vec![]));
+        assert_eq!(
+            Expr::parse("发展 经济").unwrap(),
+            Expr::And(vec![term("发展"), term("经济")])
+        );
+        assert_eq!(
+            Expr::parse(r#""hello   world" -广告"#).unwrap(),
+            Expr::And(vec![
+                Expr::Phrase("hello world".to_string()),
+                Expr::Not(Box::new(term("广告"))),
+            ])
+        );
+        assert_eq!(
+            Expr::parse("(a OR b) c").unwrap(),
+            Expr::And(vec![Expr::Or(vec![term("a"), term("b")]), term("c")])
+        );
+        assert_eq!(
+            Expr::parse("e-mail or").unwrap(),
+            Expr::And(vec![term("e-mail"), term("or")])
+        );
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert!(Expr::parse(r#""unclosed"#).is_err());
+        assert!(Expr::parse("(a OR b").is_err());
+        assert!(Expr::parse("a)").is_err());
+        assert!(Expr::parse("a OR").is_err());
+        assert!(Expr::parse("()").is_err());
+        assert!(Expr::parse("a -").is_err());
+        assert!(Expr::parse("(a OR b) (c OR d) (e OR f) (g OR h)").is_err());
+    }
+
+    #[test]
+    fn split_words_test() {
+        assert_eq!(
+            split_words(r#"a  "b -c d" -"e"  "f"#),
+            vec![
+                ("a", false),
+                (r#""b -c d""#, true),
+                (r#"-"e""#, true),
+                (r#""f"#, true)
+            ]
+        );
+        assert!(split_words("  ").is_empty());
+    }
+
+    #[test]
+    fn branches_test() {
+        let expr = Expr::parse("(a OR b) c -d").unwrap();
+        assert_eq!(
+            expr.branches(),
+            vec![vec![&term("a"), &term("c")], vec![&term("b"), &term("c")]]
+        );
+        assert!(!expr.is_simple());
+        assert_eq!(expr.terms(), vec!["a", "b", "c"]);
+    }
+}
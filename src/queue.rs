@@ -0,0 +1,375 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use teloxide::types::Message;
+use tokio::sync::Notify;
+
+use crate::{
+    config::Config,
+    db::{Db, DbResult},
+    handlers::index_message,
+};
+
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+/// Messages indexed between two acknowledgements.
+const ACK_BATCH: usize = 100;
+
+/// Write-ahead queue of messages waiting to be indexed.
+///
+/// Every message is appended to a JSON lines file before it is sent to the backend, so
+/// nothing is lost while the backend is down. The number of entries already indexed is kept
+/// next to it in `<path>.acked`, and both files are cleared once the queue is drained.
+/// Messages the backend rejects for good are moved to `<path>.failed` instead of holding up
+/// the rest.
+pub struct IndexQueue {
+    db: Db,
+    config: Arc<Config>,
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    drain: tokio::sync::Mutex<()>,
+    retry: Notify,
+}
+
+struct State {
+    file: File,
+    pending: VecDeque<Message>,
+    acked: usize,
+}
+
+impl IndexQueue {
+    /// Opens the queue stored in `QUEUE_PATH`, draining into `db`.
+    pub fn from_env(db: Db, config: Arc<Config>) -> std::io::Result<Self> {
+        Self::open(
+            std::env::var("QUEUE_PATH").unwrap_or("index_queue.jsonl".to_string()),
+            db,
+            config,
+        )
+    }
+
+    /// Opens the queue at `path`, keeping whatever was left in it by a previous run.
+    pub fn open(path: impl AsRef<Path>, db: Db, config: Arc<Config>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let acked = std::fs::read_to_string(acked_path(&path))
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+
+        let mut pending = VecDeque::new();
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines().skip(acked) {
+                // A crash in the middle of an append leaves a truncated last line behind.
+                match serde_json::from_str(&line?) {
+                    Ok(m) => pending.push_back(m),
+                    Err(e) => log::warn!("Skipping malformed entry in {}: {e}", path.display()),
+                }
+            }
+        }
+        if !pending.is_empty() {
+            log::info!("Replaying {} queued messages.", pending.len());
+        }
+
+        // Compact what is left, so acknowledged entries are never replayed twice.
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for m in &pending {
+            writeln!(file, "{}", serde_json::to_string(m)?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        remove_if_exists(&acked_path(&path))?;
+
+        Ok(IndexQueue {
+            db,
+            config,
+            state: Arc::new(Mutex::new(State {
+                file: OpenOptions::new().append(true).open(&path)?,
+                pending,
+                acked: 0,
+            })),
+            path,
+            drain: tokio::sync::Mutex::new(()),
+            retry: Notify::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Durably appends a message. It is indexed by the next `drain`.
+    pub async fn push(&self, msg: &Message) -> std::io::Result<()> {
+        let (line, msg) = (serde_json::to_string(msg)?, msg.clone());
+        self.blocking(move |state, _| {
+            writeln!(state.file, "{line}")?;
+            state.file.sync_data()?;
+            state.pending.push_back(msg);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Indexes queued messages in order, stopping at the first one that fails for a reason
+    /// that may go away. The background worker is then woken up to retry with backoff.
+    pub async fn drain(&self) -> DbResult<()> {
+        let _guard = self.drain.lock().await;
+        loop {
+            let batch = {
+                let state = self.state.lock().unwrap();
+                state
+                    .pending
+                    .iter()
+                    .take(ACK_BATCH)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            let mut indexed = 0;
+            let mut result = Ok(());
+            for msg in batch {
+                match index_message(&self.db, &self.config, &msg).await {
+                    Ok(()) => {}
+                    Err(e) if e.is_permanent() => {
+                        log::error!(
+                            "Failed to index message {} in {}, moving it to {}: {e}",
+                            msg.id,
+                            msg.chat.id,
+                            failed_path(&self.path).display()
+                        );
+                        if let Err(e) = self.fail(msg).await {
+                            log::error!("Failed to keep the message that failed: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                indexed += 1;
+            }
+            if indexed > 0 {
+                if let Err(e) = self.ack(indexed).await {
+                    log::error!("Failed to acknowledge queued messages: {e}");
+                }
+            }
+            if result.is_err() {
+                self.retry.notify_one();
+                return result;
+            }
+        }
+    }
+
+    /// Replays whatever was left over by a previous run, then keeps retrying failed drains
+    /// with exponential backoff. Never returns.
+    pub async fn run(&self) {
+        loop {
+            let mut delay = RETRY_MIN_DELAY;
+            while let Err(e) = self.drain().await {
+                log::warn!(
+                    "Failed to index {} queued messages, retrying in {delay:?}: {e}",
+                    self.len()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RETRY_MAX_DELAY);
+            }
+            self.retry.notified().await;
+        }
+    }
+
+    /// Keeps a message that can never be indexed, in the same format as the queue.
+    async fn fail(&self, msg: Message) -> std::io::Result<()> {
+        self.blocking(move |_, path| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(failed_path(path))?;
+            writeln!(file, "{}", serde_json::to_string(&msg)?)?;
+            file.sync_data()
+        })
+        .await
+    }
+
+    /// Marks the first `n` queued messages as indexed.
+    async fn ack(&self, n: usize) -> std::io::Result<()> {
+        self.blocking(move |state, path| {
+            state.pending.drain(..n.min(state.pending.len()));
+            if state.pending.is_empty() {
+                state.file.set_len(0)?;
+                state.acked = 0;
+                remove_if_exists(&acked_path(path))
+            } else {
+                state.acked += n;
+                let tmp = acked_path(path).with_extension("tmp");
+                std::fs::write(&tmp, state.acked.to_string())?;
+                std::fs::rename(tmp, acked_path(path))
+            }
+        })
+        .await
+    }
+
+    /// Runs `f` on the queue files in a blocking task, so that syncing them to disk does not
+    /// hold up the async runtime.
+    async fn blocking<T, F>(&self, f: F) -> std::io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut State, &Path) -> std::io::Result<T> + Send + 'static,
+    {
+        let (state, path) = (self.state.clone(), self.path.clone());
+        tokio::task::spawn_blocking(move || f(&mut state.lock().unwrap(), &path))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+fn acked_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".acked");
+    p.into()
+}
+
+fn failed_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".failed");
+    p.into()
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod queue_test {
+    use super::*;
+    use crate::db::{Memory, Sqlite};
+    use teloxide::types::ChatId;
+
+    fn message(id: i32) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": id,
+            "date": 1689699600,
+            "chat": {"id": -1001, "title": "Chat", "type": "supergroup", "is_forum": false},
+            "from": {"id": 1, "is_bot": false, "first_name": "User"},
+            "text": "消息",
+        }))
+        .unwrap()
+    }
+
+    fn open(path: &Path) -> IndexQueue {
+        IndexQueue::open(
+            path,
+            Db::from_backend(Memory::default()),
+            Arc::new(Config::default()),
+        )
+        .unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("index_queue_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(acked_path(&path));
+        let _ = std::fs::remove_file(failed_path(&path));
+        path
+    }
+
+    #[tokio::test]
+    async fn replay_test() {
+        let path = temp_path("replay.jsonl");
+        let queue = open(&path);
+        for id in 1..=3 {
+            queue.push(&message(id)).await.unwrap();
+        }
+        queue.ack(1).await.unwrap();
+        drop(queue);
+
+        let queue = open(&path);
+        assert_eq!(
+            queue
+                .state
+                .lock()
+                .unwrap()
+                .pending
+                .iter()
+                .map(|m| m.id.0)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_entry_test() {
+        let path = temp_path("truncated.jsonl");
+        let queue = open(&path);
+        queue.push(&message(1)).await.unwrap();
+        drop(queue);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"message_id\": 2, \"da")
+            .unwrap();
+
+        assert_eq!(open(&path).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drained_test() {
+        let path = temp_path("drained.jsonl");
+        let queue = open(&path);
+        for id in 1..=3 {
+            queue.push(&message(id)).await.unwrap();
+        }
+        queue.ack(2).await.unwrap();
+        assert_eq!(std::fs::read_to_string(acked_path(&path)).unwrap(), "2");
+        queue.ack(1).await.unwrap();
+
+        assert!(queue.is_empty());
+        assert!(!acked_path(&path).exists());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_entry_test() {
+        let path = temp_path("failed.jsonl");
+        let db_path = temp_path("failed.db");
+        let db = Db::from_backend(Sqlite::open(&db_path).unwrap());
+        db.init().await.unwrap();
+        db.insert_chat_with_id(ChatId(-1001)).await.unwrap();
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER reject BEFORE INSERT ON messages WHEN NEW.id = 2
+                BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+            )
+            .unwrap();
+
+        let queue = IndexQueue::open(&path, db.clone(), Arc::new(Config::default())).unwrap();
+        for id in 1..=3 {
+            queue.push(&message(id)).await.unwrap();
+        }
+        queue.drain().await.unwrap();
+
+        assert!(queue.is_empty());
+        assert_eq!(db.get_messages(None, 0, 10).await.unwrap().len(), 2);
+        let failed = std::fs::read_to_string(failed_path(&path)).unwrap();
+        assert_eq!(failed.lines().count(), 1);
+        assert!(failed.contains("\"message_id\":2"));
+    }
+}
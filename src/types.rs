@@ -1,12 +1,13 @@
-use std::{env, str::FromStr};
-
 use chrono::{DateTime, Utc};
-use chrono_tz::Tz;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use teloxide::types::{ChatId, MessageId};
+use teloxide::types::{ChatId, MessageId, MessageOrigin};
 
-use crate::ogp::WebPage;
+use crate::{
+    date::local_tz,
+    normalize::{normalize, variants},
+    ogp::WebPage,
+};
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 pub struct Chat {
@@ -25,33 +26,170 @@ impl From<teloxide::types::ChatId> for Chat {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Sender {
     pub id: ChatId,
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
 }
 
 impl Sender {
     pub fn from(msg: &teloxide::types::Message) -> Vec<Self> {
         vec![
             {
-                let (id, name) = msg
+                let (id, name, username) = msg
                     .sender_chat
                     .as_ref()
-                    .map(|c| (c.id, c.title().unwrap().to_string()))
-                    .unwrap_or_else(|| {
+                    .map(|c| {
                         (
-                            msg.from.as_ref().unwrap().id.into(),
-                            msg.from.as_ref().unwrap().full_name(),
+                            c.id,
+                            c.title().unwrap().to_string(),
+                            c.username().map(ToString::to_string),
                         )
+                    })
+                    .unwrap_or_else(|| {
+                        let u = msg.from.as_ref().unwrap();
+                        (u.id.into(), u.full_name(), u.username.clone())
                     });
-                Self { id, name }
+                Self { id, name, username }
             },
             Self {
                 id: msg.chat.id,
                 name: msg.chat.title().unwrap().to_string(),
+                username: msg.chat.username().map(ToString::to_string),
             },
         ]
+        .into_iter()
+        .chain(msg.forward_origin().and_then(Self::forwarded_from))
+        .collect()
+    }
+
+    /// The original sender of a forwarded message, unless they hide who they are.
+    pub fn forwarded_from(origin: &MessageOrigin) -> Option<Self> {
+        let chat = |c: &teloxide::types::Chat| Self {
+            id: c.id,
+            name: c.title().unwrap_or_default().to_string(),
+            username: c.username().map(ToString::to_string),
+        };
+        match origin {
+            MessageOrigin::User { sender_user, .. } => Some(Self {
+                id: sender_user.id.into(),
+                name: sender_user.full_name(),
+                username: sender_user.username.clone(),
+            }),
+            MessageOrigin::HiddenUser { .. } => None,
+            MessageOrigin::Chat { sender_chat, .. } => Some(chat(sender_chat)),
+            MessageOrigin::Channel { chat: c, .. } => Some(chat(c)),
+        }
+    }
+}
+
+/// A forum topic of a chat, which can be stopped from being logged on its own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Topic {
+    pub key: String,
+    pub chat_id: ChatId,
+    pub thread_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Set by `/stoptopic`, messages of the topic are not logged then.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl Topic {
+    pub fn new(chat_id: ChatId, thread_id: i32, name: Option<String>) -> Self {
+        Self {
+            key: format!("{chat_id}_{thread_id}"),
+            chat_id,
+            thread_id,
+            name,
+            disabled: false,
+        }
+    }
+
+    /// The forum topic `msg` was sent in, `None` outside of one. Its name is only known when
+    /// `msg` creates or renames the topic, or replies to the message that created it, which
+    /// topic messages do unless they reply to something else.
+    pub fn from(msg: &teloxide::types::Message) -> Option<Self> {
+        let thread_id = msg.thread_id.filter(|_| msg.is_topic_message)?.0 .0;
+        let name = msg
+            .forum_topic_created()
+            .or(msg.reply_to_message().and_then(|r| r.forum_topic_created()))
+            .map(|t| t.name.clone())
+            .or(msg.forum_topic_edited().and_then(|t| t.name.clone()));
+        Some(Self::new(msg.chat.id, thread_id, name))
+    }
+}
+
+/// What a message is besides its text, as picked with `--type`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Text,
+    Photo,
+    Video,
+    Animation,
+    Document,
+    Audio,
+    Voice,
+    Poll,
+    Venue,
+    Contact,
+}
+
+impl MessageKind {
+    pub fn of(msg: &teloxide::types::Message) -> Self {
+        if msg.photo().is_some() {
+            Self::Photo
+        } else if msg.video().is_some() {
+            Self::Video
+        } else if msg.animation().is_some() {
+            Self::Animation
+        } else if msg.document().is_some() {
+            Self::Document
+        } else if msg.audio().is_some() {
+            Self::Audio
+        } else if msg.voice().is_some() {
+            Self::Voice
+        } else if msg.poll().is_some() {
+            Self::Poll
+        } else if msg.venue().is_some() {
+            Self::Venue
+        } else if msg.contact().is_some() {
+            Self::Contact
+        } else {
+            Self::Text
+        }
+    }
+
+    /// The name used in `--type` and stored in the index.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Photo => "photo",
+            Self::Video => "video",
+            Self::Animation => "animation",
+            Self::Document => "document",
+            Self::Audio => "audio",
+            Self::Voice => "voice",
+            Self::Poll => "poll",
+            Self::Venue => "venue",
+            Self::Contact => "contact",
+        }
+    }
+}
+
+/// Characters of a replied message kept along with the reply.
+pub const REPLY_SNIPPET_LENGTH: usize = 30;
+
+/// The first `length` characters of `text` on a single line, as shown next to replies to it.
+pub fn snippet(text: &str, length: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(length) {
+        Some((i, _)) => format!("{}…", &text[..i]),
+        None => text,
     }
 }
 
@@ -59,6 +197,12 @@ impl Sender {
 pub struct Message {
     pub key: String,
     pub text: String,
+    /// `text` as rewritten by `NORMALIZE`, when that changes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized: Option<String>,
+    /// Tokens for other ways of typing `text`, such as pinyin, as set up in `NORMALIZE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub from: Option<String>,
     pub sender: Option<ChatId>,
@@ -66,18 +210,69 @@ pub struct Message {
     pub via_bot: Option<String>,
     pub id: i32,
     pub chat_id: ChatId,
+    /// The forum topic the message was sent in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<i32>,
+    /// Name of the forum topic, as of when the message was indexed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// The message this one replies to, in the same chat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i32>,
+    /// The start of the text of `reply_to`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_snippet: Option<String>,
+    /// Name of whoever the message was forwarded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_from: Option<String>,
+    /// Id of the user or chat the message was forwarded from, unless they hide it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_from_id: Option<ChatId>,
+    /// Link to the original message, when it was forwarded from a channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_link: Option<Url>,
+    /// File id of the smallest size of a photo, to send it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub photo_file_id: Option<String>,
+    /// What the message is, `None` for plain text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MessageKind>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub web_page: Option<Url>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbnail_url: Option<Url>,
     pub date: DateTime<Utc>,
+    /// `date` as seconds since the epoch, which search engines can filter and sort on.
+    #[serde(default)]
+    pub timestamp: i64,
+    /// When this version of the message was written, if it is an edit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edit_date: Option<DateTime<Utc>>,
+    /// Text before the first edit, kept when `KEEP_EDIT_HISTORY` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    /// Set on earlier versions of edited messages, to when this version was replaced as
+    /// seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revised: Option<i64>,
 }
 
 impl From<&teloxide::types::Message> for Message {
     fn from(msg: &teloxide::types::Message) -> Self {
+        let text = Self::text_of(msg).unwrap();
+        let kind = MessageKind::of(msg);
+        // Messages in forum topics reply to the message creating the topic unless they
+        // reply to something else.
+        let reply = msg
+            .reply_to_message()
+            .filter(|r| r.forum_topic_created().is_none());
+        let origin = msg.forward_origin();
+        let topic = Topic::from(msg);
         Self {
             key: format!("{}_{}", msg.chat.id, msg.id),
-            text: msg.text().or(msg.caption()).unwrap().to_string(),
+            normalized: normalize(&text),
+            variants: variants(&text),
+            text,
             from: None,
             sender: Some(
                 msg.sender_chat
@@ -90,26 +285,109 @@ impl From<&teloxide::types::Message> for Message {
                 .as_ref()
                 .map(|u| format!("@{}", u.username.clone().unwrap())),
             id: msg.id.0,
+            thread_id: topic.as_ref().map(|t| t.thread_id),
+            topic: topic.and_then(|t| t.name),
+            reply_to: reply.map(|r| r.id.0),
+            reply_snippet: reply
+                .and_then(Self::text_of)
+                .map(|t| snippet(&t, REPLY_SNIPPET_LENGTH)),
+            forward_from: origin.map(|o| match (o, Sender::forwarded_from(o)) {
+                (
+                    MessageOrigin::HiddenUser {
+                        sender_user_name, ..
+                    },
+                    _,
+                ) => sender_user_name.clone(),
+                (_, s) => s.map(|s| s.name).unwrap_or_default(),
+            }),
+            forward_from_id: origin.and_then(Sender::forwarded_from).map(|s| s.id),
+            forward_link: match origin {
+                Some(MessageOrigin::Channel {
+                    chat, message_id, ..
+                }) => teloxide::types::Message::url_of(chat.id, chat.username(), *message_id),
+                _ => None,
+            },
+            photo_file_id: msg.photo().and_then(|p| {
+                p.iter()
+                    .min_by_key(|s| s.width * s.height)
+                    .map(|s| s.file.id.clone())
+            }),
+            kind: (kind != MessageKind::Text).then_some(kind),
             web_page: None,
             thumbnail_url: None,
             chat_id: msg.chat.id,
             date: msg.date,
+            timestamp: msg.date.timestamp(),
+            edit_date: msg.edit_date().copied(),
+            original: None,
+            revised: None,
         }
     }
 }
 
 impl Message {
+    /// The text to index for `msg`: its text or caption, followed by what its media tells
+    /// about itself, such as the name of a document or the options of a poll. `None` when
+    /// there is nothing to search for.
+    pub fn text_of(msg: &teloxide::types::Message) -> Option<String> {
+        let join = |parts: &[Option<&str>], sep| {
+            parts
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>()
+                .join(sep)
+        };
+        let media = [
+            msg.document().and_then(|d| d.file_name.clone()),
+            msg.audio().map(
+                |a| match join(&[a.performer.as_deref(), a.title.as_deref()], " - ") {
+                    t if t.is_empty() => a.file_name.clone().unwrap_or_default(),
+                    t => t,
+                },
+            ),
+            msg.poll().map(|p| {
+                std::iter::once(p.question.as_str())
+                    .chain(p.options.iter().map(|o| o.text.as_str()))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+            msg.venue()
+                .map(|v| join(&[Some(&v.title), Some(&v.address)], "\n")),
+            msg.contact()
+                .map(|c| join(&[Some(&c.first_name), c.last_name.as_deref()], " ")),
+        ];
+
+        let text = msg
+            .text()
+            .or(msg.caption())
+            .map(ToString::to_string)
+            .into_iter()
+            .chain(media.into_iter().flatten())
+            .filter(|t| !t.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        (!text.is_empty()).then_some(text)
+    }
+
     pub fn format_time(&self) -> String {
         self.date
-            .with_timezone(&Tz::from_str(&env::var("TZ").unwrap_or_default()).unwrap_or_default())
+            .with_timezone(&local_tz())
             .format("%Y-%m-%d")
             .to_string()
     }
 
+    /// Link to the message, which opens its forum topic if it has one.
     pub fn link(&self) -> String {
-        teloxide::types::Message::url_of(self.chat_id, None, MessageId(self.id))
-            .unwrap()
-            .to_string()
+        let mut url =
+            teloxide::types::Message::url_of(self.chat_id, None, MessageId(self.id)).unwrap();
+        if let Some(thread_id) = self.thread_id {
+            let path = url.path().replacen("/c/", "", 1);
+            if let Some((chat, id)) = path.split_once('/') {
+                url.set_path(&format!("/c/{chat}/{thread_id}/{id}"));
+            }
+        }
+        url.to_string()
     }
 
     pub fn set_web_page(mut self, page: &WebPage) -> Self {
@@ -122,8 +400,25 @@ impl Message {
         );
         self.text = html_escape::decode_html_entities(&format!("{}\n{}", page.title, page.desc))
             .to_string();
+        self.normalized = normalize(&self.text);
+        self.variants = variants(&self.text);
         self
     }
+
+    /// Turns this version into an earlier one of the message, replaced at `replaced`.
+    pub fn into_revision(self, replaced: DateTime<Utc>) -> Self {
+        Self {
+            key: format!("{}_r{}", self.key, replaced.timestamp()),
+            original: None,
+            revised: Some(replaced.timestamp()),
+            ..self
+        }
+    }
+
+    /// The text search matches against, the same length in bytes as `text`.
+    pub fn search_text(&self) -> &str {
+        self.normalized.as_deref().unwrap_or(&self.text)
+    }
 }
 
 #[cfg(test)]
@@ -303,7 +598,7 @@ mod types_tests {
             },
             "photo": [
                 {
-                    "file_id": "0-1",
+                    "file_id": "0-small",
                     "file_unique_id": "2",
                     "file_size": 1224,
                     "width": 90,
@@ -326,7 +621,8 @@ mod types_tests {
             )
             .unwrap(),
         );
-        assert_eq!(msg.text, "112")
+        assert_eq!(msg.text, "112");
+        assert_eq!(msg.photo_file_id.unwrap(), "0-small");
     }
 
     #[test]
@@ -380,6 +676,57 @@ mod types_tests {
         assert_eq!(msg.link(), "https://t.me/c/1952114514/3")
     }
 
+    #[test]
+    fn message_topic_test() {
+        let msg = Message::from(
+            &serde_json::from_str::<teloxide::types::Message>(
+                r#"{
+            "message_id": 5,
+            "message_thread_id": 4,
+            "date": 1689731481,
+            "chat": {
+                "id": -1001952114514,
+                "title": "test",
+                "type": "supergroup",
+                "is_forum": true
+            },
+            "from": {
+                "id": 1,
+                "is_bot": false,
+                "first_name": "Foo"
+            },
+            "reply_to_message": {
+                "message_id": 4,
+                "message_thread_id": 4,
+                "date": 1689731400,
+                "chat": {
+                    "id": -1001952114514,
+                    "title": "test",
+                    "type": "supergroup",
+                    "is_forum": true
+                },
+                "from": {
+                    "id": 1,
+                    "is_bot": false,
+                    "first_name": "Foo"
+                },
+                "forum_topic_created": {
+                    "name": "Rust",
+                    "icon_color": 7322096
+                },
+                "is_topic_message": true
+            },
+            "text": "blah",
+            "is_topic_message": true
+        }"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(msg.thread_id, Some(4));
+        assert_eq!(msg.topic.as_deref(), Some("Rust"));
+        assert_eq!(msg.link(), "https://t.me/c/1952114514/4/5")
+    }
+
     #[test]
     fn message_no_via_bot_test() {
         let msg = Message::from(
@@ -454,4 +801,84 @@ mod types_tests {
         );
         assert_eq!(msg.via_bot.unwrap(), "@TestBot");
     }
+
+    #[test]
+    fn message_text_from_document_test() {
+        let msg = Message::from(
+            &serde_json::from_str::<teloxide::types::Message>(
+                r#"{
+            "message_id": 3,
+            "date": 1689731481,
+            "chat": {
+                "id": -1001,
+                "title": "test",
+                "type": "supergroup",
+                "is_forum": false
+            },
+            "from": {
+                "id": 1,
+                "is_bot": false,
+                "first_name": "Foo"
+            },
+            "document": {
+                "file_id": "0-1",
+                "file_unique_id": "2",
+                "file_name": "年度报告.pdf",
+                "mime_type": "application/pdf"
+            }
+        }"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(msg.text, "年度报告.pdf");
+        assert_eq!(msg.kind, Some(MessageKind::Document));
+    }
+
+    #[test]
+    fn message_text_from_poll_test() {
+        let msg = Message::from(
+            &serde_json::from_str::<teloxide::types::Message>(
+                r#"{
+            "message_id": 3,
+            "date": 1689731481,
+            "chat": {
+                "id": -1001,
+                "title": "test",
+                "type": "supergroup",
+                "is_forum": false
+            },
+            "from": {
+                "id": 1,
+                "is_bot": false,
+                "first_name": "Foo"
+            },
+            "poll": {
+                "id": "1",
+                "question": "午饭吃什么",
+                "options": [
+                    {"text": "拉面", "voter_count": 0},
+                    {"text": "寿司", "voter_count": 0}
+                ],
+                "total_voter_count": 0,
+                "is_closed": false,
+                "is_anonymous": true,
+                "type": "regular",
+                "allows_multiple_answers": false
+            }
+        }"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(msg.text, "午饭吃什么\n拉面\n寿司");
+        assert_eq!(msg.kind, Some(MessageKind::Poll));
+    }
+
+    #[test]
+    fn snippet_test() {
+        assert_eq!(snippet("周末\n去爬山吗", 30), "周末 去爬山吗");
+        assert_eq!(
+            snippet(&"很长".repeat(20), 30),
+            format!("{}…", "很长".repeat(15))
+        );
+    }
 }
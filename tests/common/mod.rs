@@ -0,0 +1,357 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::{body::Bytes, extract::Path, extract::State, routing::post, Json, Router};
+use serde_json::{json, Value};
+use teloxide::{
+    prelude::*,
+    types::{InlineQuery, Me, Message},
+};
+
+pub const BOT_ID: u64 = 10;
+pub const BOT_USERNAME: &str = "test_bot";
+
+/// A minimal Telegram Bot API server recording every request it gets.
+#[derive(Clone, Default)]
+pub struct FakeApi {
+    requests: Arc<Mutex<Vec<(String, Value)>>>,
+    statuses: Arc<Mutex<HashMap<(i64, u64), String>>>,
+    deleted: Arc<Mutex<HashSet<(i64, i64)>>>,
+    protected: Arc<Mutex<HashSet<(i64, i64)>>>,
+    channels: Arc<Mutex<HashMap<i64, i64>>>,
+}
+
+impl FakeApi {
+    /// Starts the server and returns a bot talking to it.
+    pub async fn start() -> (Self, Bot) {
+        let api = FakeApi::default();
+        let app = Router::new()
+            .route("/{token}/{method}", post(handle))
+            .with_state(api.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let bot = Bot::new("1:token").set_api_url(format!("http://{addr}/").parse().unwrap());
+        (api, bot)
+    }
+
+    /// Members are creators of every chat unless told otherwise.
+    pub fn set_status(&self, chat_id: i64, user_id: u64, status: &str) {
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert((chat_id, user_id), status.to_string());
+    }
+
+    /// Makes a message disappear, as if it had been deleted in the chat.
+    pub fn delete_message(&self, chat_id: i64, message_id: i64) {
+        self.deleted.lock().unwrap().insert((chat_id, message_id));
+    }
+
+    /// Makes a message impossible to copy, as if it had protected content.
+    pub fn protect_message(&self, chat_id: i64, message_id: i64) {
+        self.protected.lock().unwrap().insert((chat_id, message_id));
+    }
+
+    /// Makes `channel_id` a channel whose discussion group is `group_id`.
+    pub fn link_channel(&self, channel_id: i64, group_id: i64) {
+        self.channels.lock().unwrap().insert(channel_id, group_id);
+    }
+
+    pub fn requests(&self, method: &str) -> Vec<Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, _)| m.eq_ignore_ascii_case(method))
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+}
+
+async fn handle(
+    State(api): State<FakeApi>,
+    Path((_, method)): Path<(String, String)>,
+    body: Bytes,
+) -> Json<Value> {
+    let params: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    api.requests
+        .lock()
+        .unwrap()
+        .push((method.clone(), params.clone()));
+
+    // Method names are case-insensitive, and teloxide sends them capitalized.
+    let result = match method.to_lowercase().as_str() {
+        "getchatmember" => {
+            let (chat_id, user_id) = (
+                params["chat_id"].as_i64().unwrap(),
+                params["user_id"].as_u64().unwrap(),
+            );
+            let status = api
+                .statuses
+                .lock()
+                .unwrap()
+                .get(&(chat_id, user_id))
+                .cloned()
+                .unwrap_or("creator".to_string());
+            json!({
+                "user": user(user_id),
+                "status": status,
+                "is_anonymous": false,
+            })
+        }
+        "getchat" => {
+            let chat_id = params["chat_id"].as_i64().unwrap();
+            let channels = api.channels.lock().unwrap();
+            if chat_id > 0 {
+                json!({"id": chat_id, "type": "private", "first_name": format!("User {chat_id}")})
+            } else if let Some(group_id) = channels.get(&chat_id) {
+                let mut c = channel(chat_id);
+                c["linked_chat_id"] = json!(group_id);
+                c
+            } else if let Some((channel_id, _)) = channels.iter().find(|(_, g)| **g == chat_id) {
+                let mut c = chat(chat_id);
+                c["linked_chat_id"] = json!(channel_id);
+                c
+            } else {
+                chat(chat_id)
+            }
+        }
+        "copymessages" => {
+            let chat_id = params["from_chat_id"].as_i64().unwrap();
+            let protected = api.protected.lock().unwrap();
+            if params["message_ids"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|id| protected.contains(&(chat_id, id.as_i64().unwrap())))
+            {
+                return Json(cannot_copy());
+            }
+            let deleted = api.deleted.lock().unwrap();
+            params["message_ids"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|id| !deleted.contains(&(chat_id, id.as_i64().unwrap())))
+                .map(|id| json!({"message_id": id}))
+                .collect()
+        }
+        "copymessage" => {
+            let (chat_id, message_id) = (
+                params["from_chat_id"].as_i64().unwrap(),
+                params["message_id"].as_i64().unwrap(),
+            );
+            if api
+                .protected
+                .lock()
+                .unwrap()
+                .contains(&(chat_id, message_id))
+            {
+                return Json(cannot_copy());
+            }
+            if api.deleted.lock().unwrap().contains(&(chat_id, message_id)) {
+                return Json(json!({
+                    "ok": false,
+                    "error_code": 400,
+                    "description": "Bad Request: message to copy not found",
+                }));
+            }
+            json!({"message_id": message_id})
+        }
+        "sendmessage" => json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": chat(params["chat_id"].as_i64().unwrap()),
+            "from": user(BOT_ID),
+            "text": params["text"],
+        }),
+        _ => json!(true),
+    };
+    Json(json!({"ok": true, "result": result}))
+}
+
+fn cannot_copy() -> Value {
+    json!({
+        "ok": false,
+        "error_code": 400,
+        "description": "Bad Request: message can't be copied",
+    })
+}
+
+fn user(id: u64) -> Value {
+    json!({"id": id, "is_bot": id == BOT_ID, "first_name": format!("User {id}")})
+}
+
+fn chat(id: i64) -> Value {
+    json!({"id": id, "title": format!("Chat {id}"), "type": "supergroup", "is_forum": false})
+}
+
+fn channel(id: i64) -> Value {
+    json!({"id": id, "title": format!("Channel {id}"), "type": "channel"})
+}
+
+pub fn me() -> Me {
+    serde_json::from_value(json!({
+        "id": BOT_ID,
+        "is_bot": true,
+        "first_name": "Test Bot",
+        "username": BOT_USERNAME,
+        "can_join_groups": true,
+        "can_read_all_group_messages": true,
+        "supports_inline_queries": true,
+    }))
+    .unwrap()
+}
+
+pub fn message(chat_id: i64, user_id: u64, message_id: i32, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// Message `message_id` after it was edited to `text` a minute later.
+pub fn edited_message(chat_id: i64, user_id: u64, message_id: i32, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "edit_date": 1689699660 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// A reply to `reply_to`.
+pub fn reply_message(
+    chat_id: i64,
+    user_id: u64,
+    message_id: i32,
+    reply_to: &Message,
+    text: &str,
+) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "reply_to_message": reply_to,
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// A message forwarded from post `post_id` of the channel `channel_id`.
+pub fn forwarded_message(
+    chat_id: i64,
+    user_id: u64,
+    message_id: i32,
+    (channel_id, post_id): (i64, i32),
+    text: &str,
+) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "forward_origin": {
+            "type": "channel",
+            "date": 1689699000,
+            "chat": channel(channel_id),
+            "message_id": post_id,
+        },
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// A photo in two sizes, `small` and `large`, with a caption.
+pub fn photo_message(chat_id: i64, user_id: u64, message_id: i32, caption: &str) -> Message {
+    let size = |id: &str, width: u32| json!({"file_id": id, "file_unique_id": id, "file_size": width * 100, "width": width, "height": width});
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "photo": [size("large", 1280), size("small", 90)],
+        "caption": caption,
+    }))
+    .unwrap()
+}
+
+/// A message sent to the bot in a private chat.
+pub fn private_message(user_id: u64, message_id: i32, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": {"id": user_id, "type": "private", "first_name": format!("User {user_id}")},
+        "from": user(user_id),
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// A message in the forum topic started by message `thread_id`.
+pub fn topic_message(
+    chat_id: i64,
+    user_id: u64,
+    message_id: i32,
+    thread_id: i32,
+    text: &str,
+) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "message_thread_id": thread_id,
+        "is_topic_message": true,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "text": text,
+    }))
+    .unwrap()
+}
+
+/// The service message creating a forum topic.
+pub fn topic_created(chat_id: i64, user_id: u64, message_id: i32, name: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "message_thread_id": message_id,
+        "is_topic_message": true,
+        "date": 1689699600 + message_id,
+        "chat": chat(chat_id),
+        "from": user(user_id),
+        "forum_topic_created": {"name": name, "icon_color": 7322096},
+    }))
+    .unwrap()
+}
+
+/// A post in a channel, which comes from the channel itself.
+pub fn channel_post(chat_id: i64, message_id: i32, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": message_id,
+        "date": 1689699600 + message_id,
+        "chat": channel(chat_id),
+        "sender_chat": channel(chat_id),
+        "text": text,
+    }))
+    .unwrap()
+}
+
+pub fn inline_query(user_id: u64, query: &str, offset: &str) -> InlineQuery {
+    serde_json::from_value(json!({
+        "id": "1",
+        "from": user(user_id),
+        "query": query,
+        "offset": offset,
+    }))
+    .unwrap()
+}
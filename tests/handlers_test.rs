@@ -0,0 +1,653 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::*;
+use telegram_cjk_search_bot::{
+    config::Config,
+    db::{Db, Memory},
+    handlers::*,
+    prune::Pruner,
+    queue::IndexQueue,
+};
+use teloxide::{
+    prelude::*,
+    types::{ChatId, InlineQuery, UserId},
+};
+
+const SCRATCH_CHAT_ID: i64 = 100;
+
+/// Handler dependencies of a single test, backed by their own in-memory database.
+struct Deps {
+    db: Db,
+    queue: Arc<IndexQueue>,
+    pruner: Arc<Pruner>,
+    config: Arc<Config>,
+}
+
+impl Deps {
+    async fn message(&self, bot: &Bot, msg: Message) {
+        message_handler(
+            bot.clone(),
+            msg,
+            me(),
+            self.db.clone(),
+            self.queue.clone(),
+            self.pruner.clone(),
+            self.config.clone(),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn inline(&self, bot: &Bot, q: InlineQuery) {
+        inline_handler(bot.clone(), q, self.db.clone())
+            .await
+            .unwrap();
+    }
+}
+
+fn setup(name: &str) -> Deps {
+    setup_with(name, Config::default())
+}
+
+fn setup_with(name: &str, config: Config) -> Deps {
+    telegram_cjk_search_bot::BOT_USERNAME.get_or_init(|| format!("@{BOT_USERNAME}"));
+    let db = Db::from_backend(Memory::default());
+    let config = Arc::new(config);
+    let path = std::env::temp_dir().join(format!("{name}_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    Deps {
+        queue: Arc::new(IndexQueue::open(path, db.clone(), config.clone()).unwrap()),
+        pruner: Arc::new(Pruner::new(Some(ChatId(SCRATCH_CHAT_ID)), Duration::ZERO)),
+        db,
+        config,
+    }
+}
+
+#[tokio::test]
+async fn start_test() {
+    let deps = setup("start_test");
+    let (api, bot) = FakeApi::start().await;
+
+    deps.message(&bot, message(-1001000000001, 1, 1, "/start"))
+        .await;
+
+    assert!(deps
+        .db
+        .filter_chat_with_id(ChatId(-1001000000001))
+        .await
+        .unwrap()
+        .is_some());
+    assert!(api.requests("sendMessage")[0]["text"]
+        .as_str()
+        .unwrap()
+        .ends_with("has started to log messages."));
+}
+
+#[tokio::test]
+async fn start_not_privileged_test() {
+    let deps = setup("start_not_privileged_test");
+    let (api, bot) = FakeApi::start().await;
+    api.set_status(-1001000000002, 2, "member");
+
+    deps.message(&bot, message(-1001000000002, 2, 1, "/start"))
+        .await;
+
+    assert!(deps
+        .db
+        .filter_chat_with_id(ChatId(-1001000000002))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(api.requests("sendMessage")[0]["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("You need to be either Admin or Owner"));
+}
+
+#[tokio::test]
+async fn search_test() {
+    let deps = setup("search_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000003, 3);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for (id, text) in [(2, "经济发展很快"), (3, "今天天气不错")] {
+        deps.message(&bot, message(chat, user, id, text)).await;
+    }
+    deps.message(&bot, message(-1001000000004, user, 1, "发展是硬道理"))
+        .await;
+
+    deps.inline(&bot, inline_query(user, "发展", "")).await;
+
+    let answer = &api.requests("answerInlineQuery")[0];
+    let results = answer["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["id"], format!("{chat}_2"));
+    assert_eq!(results[0]["title"], "经济发展很快");
+    assert_eq!(results[1]["title"], "No more.");
+    assert_eq!(answer["next_offset"], "");
+}
+
+#[tokio::test]
+async fn pagination_test() {
+    let deps = setup("pagination_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000005, 5);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for id in 2..27 {
+        deps.message(&bot, message(chat, user, id, &format!("消息 {id}")))
+            .await;
+    }
+
+    deps.inline(&bot, inline_query(user, "消息", "")).await;
+    deps.inline(&bot, inline_query(user, "消息", "20")).await;
+
+    let answers = api.requests("answerInlineQuery");
+    assert_eq!(answers[0]["results"].as_array().unwrap().len(), 20);
+    assert_eq!(answers[0]["next_offset"], "20");
+    let results = answers[1]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 6);
+    assert_eq!(results[5]["title"], "No more.");
+    assert_eq!(answers[1]["next_offset"], "");
+}
+
+#[tokio::test]
+async fn prune_test() {
+    let deps = setup("prune_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000006, 6);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for id in 2..5 {
+        deps.message(&bot, message(chat, user, id, &format!("消息 {id}")))
+            .await;
+    }
+    api.delete_message(chat, 3);
+    // A message that cannot be copied is kept rather than failing the whole batch.
+    api.protect_message(chat, 4);
+    deps.message(&bot, message(chat, user, 5, "/prune")).await;
+
+    // Pruning runs in the background and reports back when it is done.
+    let reply = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(r) = api.requests("sendMessage").get(2) {
+                return r["text"].as_str().unwrap().to_string();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(reply, "Removed 1 deleted messages from search results.");
+
+    deps.inline(&bot, inline_query(user, "消息", "")).await;
+    let results = api.requests("answerInlineQuery")[0]["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["id"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![
+            format!("{chat}_4"),
+            format!("{chat}_2"),
+            "empty".to_string()
+        ]
+    );
+    // Copies left in the scratch chat are cleaned up.
+    assert!(!api.requests("deleteMessages").is_empty());
+}
+
+#[tokio::test]
+async fn date_filter_test() {
+    let deps = setup("date_filter_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000007, 7);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for (id, date) in [(2, "2023-07-19"), (3, "2023-08-15"), (4, "2023-09-10")] {
+        let mut msg = message(chat, user, id, "消息");
+        msg.date = format!("{date}T12:00:00Z").parse().unwrap();
+        deps.message(&bot, msg).await;
+    }
+
+    deps.inline(&bot, inline_query(user, "消息 --since 2023-08", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -s 2023-08 -u 2023-08", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -ms2023-08 -au 2023-08", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -sq 2023-08", ""))
+        .await;
+
+    let ids = |answer: &serde_json::Value| {
+        let mut ids = answer["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["id"].as_str().unwrap().to_string())
+            .filter(|id| id != "empty")
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    let answers = api.requests("answerInlineQuery");
+    assert_eq!(ids(&answers[0]), [format!("{chat}_3"), format!("{chat}_4")]);
+    assert_eq!(ids(&answers[1]), [format!("{chat}_3")]);
+    assert_eq!(ids(&answers[2]), [format!("{chat}_3")]);
+    assert_eq!(answers[3]["results"][0]["title"], "Parse Error!");
+}
+
+#[tokio::test]
+async fn sender_filter_test() {
+    let deps = setup("sender_filter_test");
+    let (api, bot) = FakeApi::start().await;
+    let chat = -1001000000008;
+
+    deps.message(&bot, message(chat, 8, 1, "/start")).await;
+    deps.message(&bot, message(chat, 8, 2, "消息")).await;
+    deps.message(&bot, message(chat, 9, 3, "消息")).await;
+
+    deps.inline(&bot, inline_query(8, "消息 -f 9", "")).await;
+    deps.inline(&bot, inline_query(8, "消息 --from user", ""))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    let results = answers[0]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["id"], format!("{chat}_3"));
+
+    let suggestions = answers[1]["results"].as_array().unwrap();
+    assert_eq!(suggestions.len(), 2);
+    assert_eq!(suggestions[0]["title"], "User 8");
+    assert_eq!(
+        suggestions[0]["reply_markup"]["inline_keyboard"][0][0]["switch_inline_query_current_chat"],
+        "消息 --from 8"
+    );
+}
+
+#[tokio::test]
+async fn chat_filter_test() {
+    let deps = setup("chat_filter_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chats, user) = ([-1001000000010, -1001000000011], 10);
+
+    for chat in chats {
+        deps.message(&bot, message(chat, user, 1, "/start")).await;
+        deps.message(&bot, message(chat, user, 2, "消息")).await;
+    }
+
+    deps.inline(&bot, inline_query(user, "消息 --chat -1001000000010", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -x 0000010", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -c 0000010 -x 0000010", ""))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    let first_id = |i: usize| answers[i]["results"][0]["id"].clone();
+    assert_eq!(first_id(0), format!("{}_2", chats[0]));
+    assert_eq!(first_id(1), format!("{}_2", chats[1]));
+    assert_eq!(first_id(2), "empty");
+}
+
+#[tokio::test]
+async fn sort_test() {
+    let deps = setup("sort_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000012, 12);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for id in 2..27 {
+        deps.message(&bot, message(chat, user, id, "消息")).await;
+    }
+
+    deps.inline(&bot, inline_query(user, "消息 --sort newest", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 --sort oldest", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 --sort oldest", "20"))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    let first_id = |i: usize| answers[i]["results"][0]["id"].clone();
+    assert_eq!(first_id(0), format!("{chat}_26"));
+    assert_eq!(first_id(1), format!("{chat}_2"));
+    assert_eq!(first_id(2), format!("{chat}_22"));
+    assert_eq!(answers[1]["next_offset"], "20");
+}
+
+#[tokio::test]
+async fn query_operators_test() {
+    let deps = setup("query_operators_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000013, 13);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    deps.message(&bot, message(chat, user, 2, "出售 广告"))
+        .await;
+    deps.message(&bot, message(chat, user, 3, "出售 二手书"))
+        .await;
+    deps.message(&bot, message(chat, user, 4, "构建 error -c build 失败"))
+        .await;
+
+    deps.inline(&bot, inline_query(user, "出售 -广告 --sort newest", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "(出售 OR", "")).await;
+    deps.inline(&bot, inline_query(user, r#""error -c build""#, ""))
+        .await;
+    deps.inline(&bot, inline_query(user, r#"出售 -"广告" -s 2023-07"#, ""))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    let results = answers[0]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["id"], format!("{chat}_3"));
+    assert_eq!(answers[1]["results"][0]["title"], "Parse Error!");
+    assert_eq!(answers[2]["results"][0]["id"], format!("{chat}_4"));
+    assert_eq!(answers[3]["results"][0]["id"], format!("{chat}_3"));
+}
+
+#[tokio::test]
+async fn topic_test() {
+    let deps = setup("topic_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000014, 14);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    deps.message(&bot, topic_created(chat, user, 2, "Rust"))
+        .await;
+    deps.message(&bot, topic_message(chat, user, 3, 2, "消息"))
+        .await;
+    deps.message(&bot, message(chat, user, 4, "消息")).await;
+    deps.message(&bot, topic_message(chat, user, 5, 2, "/stoptopic"))
+        .await;
+    deps.message(&bot, topic_message(chat, user, 6, 2, "消息"))
+        .await;
+
+    deps.inline(&bot, inline_query(user, "消息 --topic rust", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "消息 -t 3", "")).await;
+
+    assert_eq!(
+        api.requests("sendMessage")[1]["text"],
+        "Topic Rust(2) has stopped to log messages."
+    );
+    let answers = api.requests("answerInlineQuery");
+    let results = answers[0]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["id"], format!("{chat}_3"));
+    assert!(results[0]["description"]
+        .as_str()
+        .unwrap()
+        .contains(" › Rust"));
+    assert!(results[0]["input_message_content"]["message_text"]
+        .as_str()
+        .unwrap()
+        .contains("https://t.me/c/1000000014/2/3"));
+    assert_eq!(answers[1]["results"][0]["id"], "empty");
+}
+
+#[tokio::test]
+async fn channel_test() {
+    let deps = setup("channel_test");
+    let (api, bot) = FakeApi::start().await;
+    let (channel, group, admin, subscriber, stranger) =
+        (-1001000000019, -1001000000119, 19, 20, 21);
+    api.link_channel(channel, group);
+    api.set_status(channel, subscriber, "member");
+    api.set_status(channel, stranger, "left");
+
+    deps.message(&bot, message(group, admin, 1, "/startchannel"))
+        .await;
+    deps.message(&bot, channel_post(channel, 2, "频道公告"))
+        .await;
+    deps.inline(&bot, inline_query(subscriber, "公告", ""))
+        .await;
+    deps.inline(&bot, inline_query(stranger, "公告", "")).await;
+    deps.message(
+        &bot,
+        private_message(subscriber, 3, &format!("/stopchannel {channel}")),
+    )
+    .await;
+
+    let replies = api.requests("sendMessage");
+    assert_eq!(
+        replies[0]["text"],
+        format!("Channel Channel {channel}({channel}) has started to log messages.")
+    );
+    assert!(replies[1]["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("You need to be either Admin or Owner of this channel"));
+    assert!(deps
+        .db
+        .filter_chat_with_id(ChatId(channel))
+        .await
+        .unwrap()
+        .is_some());
+
+    let answers = api.requests("answerInlineQuery");
+    assert_eq!(answers[0]["results"][0]["id"], format!("{channel}_2"));
+    assert!(answers[0]["results"][0]["description"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("Channel {channel}@20")));
+    assert_eq!(answers[1]["results"][0]["id"], "empty");
+}
+
+#[tokio::test]
+async fn history_test() {
+    let deps = setup_with(
+        "history_test",
+        Config {
+            keep_edit_history: true,
+            ..Default::default()
+        },
+    );
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000021, 21);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    deps.message(&bot, message(chat, user, 2, "旧的说法")).await;
+    deps.message(&bot, edited_message(chat, user, 2, "新的说法"))
+        .await;
+
+    deps.inline(&bot, inline_query(user, "旧的", "")).await;
+    deps.inline(&bot, inline_query(user, "旧的 --history", ""))
+        .await;
+    deps.inline(&bot, inline_query(user, "新的 --history", ""))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    assert_eq!(answers[0]["results"][0]["id"], "empty");
+    let revision = &answers[1]["results"][0];
+    assert_eq!(revision["id"], format!("{chat}_2_r1689699662"));
+    assert!(revision["description"]
+        .as_str()
+        .unwrap()
+        .contains(" (edited "));
+    assert!(
+        answers[2]["results"][0]["input_message_content"]["message_text"]
+            .as_str()
+            .unwrap()
+            .ends_with("Originally: 「 旧的说法 」")
+    );
+}
+
+#[tokio::test]
+async fn reply_forward_test() {
+    let deps = setup("reply_forward_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user, channel) = (-1001000000022, 22, -1001000000122);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    let original = message(chat, user, 2, "周末去爬山吗");
+    deps.message(&bot, original.clone()).await;
+    deps.message(&bot, reply_message(chat, user, 3, &original, "好啊"))
+        .await;
+    deps.message(
+        &bot,
+        forwarded_message(chat, user, 4, (channel, 7), "好消息"),
+    )
+    .await;
+
+    deps.inline(&bot, inline_query(user, "好 --replies-to 2", ""))
+        .await;
+    deps.inline(
+        &bot,
+        inline_query(user, "好 --replies-to https://t.me/c/1000000022/2", ""),
+    )
+    .await;
+    deps.inline(
+        &bot,
+        inline_query(user, &format!("好 --forwarded-from {channel}"), ""),
+    )
+    .await;
+
+    let answers = api.requests("answerInlineQuery");
+    for answer in &answers[..2] {
+        let results = answer["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["id"], format!("{chat}_3"));
+        assert!(results[0]["description"]
+            .as_str()
+            .unwrap()
+            .ends_with(", in reply to 「周末去爬山吗」"));
+    }
+    let results = answers[2]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["id"], format!("{chat}_4"));
+    assert!(results[0]["input_message_content"]["message_text"]
+        .as_str()
+        .unwrap()
+        .contains(r#", forwarded from <a href="https://t.me/c/1000000122/7">"#));
+}
+
+#[tokio::test]
+async fn reply_chats_test() {
+    let deps = setup("reply_chats_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chats, user) = ([-1001000000026, -1001000000027], 26);
+
+    for chat in chats {
+        deps.message(&bot, message(chat, user, 1, "/start")).await;
+        let original = message(chat, user, 2, "周末去爬山吗");
+        deps.message(&bot, original.clone()).await;
+        deps.message(&bot, reply_message(chat, user, 3, &original, "好啊"))
+            .await;
+    }
+
+    deps.inline(&bot, inline_query(user, "好 --replies-to 2", ""))
+        .await;
+    deps.inline(
+        &bot,
+        inline_query(user, "好 --replies-to https://t.me/c/1000000027/2", ""),
+    )
+    .await;
+    deps.inline(
+        &bot,
+        inline_query(user, "好 --replies-to 2 --chat -1001000000026", ""),
+    )
+    .await;
+
+    let answers = api.requests("answerInlineQuery");
+    assert_eq!(answers[0]["results"][0]["id"], "no_message");
+    for (answer, chat) in answers[1..].iter().zip([chats[1], chats[0]]) {
+        let results = answer["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["id"], format!("{chat}_3"));
+    }
+}
+
+#[tokio::test]
+async fn context_test() {
+    let deps = setup("context_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000023, 23);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    for (id, text) in [
+        (2, "早"),
+        (3, "今天开会吗"),
+        (4, "下午三点"),
+        (5, "好的"),
+        (6, "晚安"),
+    ] {
+        deps.message(&bot, message(chat, user, id, text)).await;
+    }
+
+    deps.inline(&bot, inline_query(user, "三点 --context 1", ""))
+        .await;
+
+    let answers = api.requests("answerInlineQuery");
+    let text = answers[0]["results"][0]["input_message_content"]["message_text"]
+        .as_str()
+        .unwrap();
+    assert!(
+        text.starts_with("User 23: 今天开会吗\n<b>User 23: 下午三点</b>\nUser 23: 好的\n\n— from ")
+    );
+}
+
+#[tokio::test]
+async fn photo_test() {
+    let deps = setup("photo_test");
+    let (api, bot) = FakeApi::start().await;
+    let (chat, user) = (-1001000000024, 24);
+
+    deps.message(&bot, message(chat, user, 1, "/start")).await;
+    deps.message(&bot, photo_message(chat, user, 2, "海边日落"))
+        .await;
+
+    deps.message(
+        &bot,
+        photo_message(chat, user, 3, &format!("山顶日出{}", "🌄".repeat(1000))),
+    )
+    .await;
+
+    deps.inline(&bot, inline_query(user, "日落", "")).await;
+    deps.inline(&bot, inline_query(user, "日出", "")).await;
+
+    let answers = api.requests("answerInlineQuery");
+    let result = &answers[0]["results"][0];
+    assert_eq!(result["type"], "photo");
+    assert_eq!(result["photo_file_id"], "small");
+    assert!(result["caption"]
+        .as_str()
+        .unwrap()
+        .starts_with("「 海边日落 」"));
+    let caption = answers[1]["results"][0]["caption"].as_str().unwrap();
+    assert!(caption.encode_utf16().count() <= 1024);
+    assert!(caption.contains("... 」 from <a href="));
+}
+
+#[tokio::test]
+async fn reload_test() {
+    let deps = setup_with(
+        "reload_test",
+        Config {
+            operator: Some(UserId(25)),
+            ..Default::default()
+        },
+    );
+    let (api, bot) = FakeApi::start().await;
+
+    deps.message(&bot, message(-1001000000025, 1, 1, "/reload"))
+        .await;
+    deps.message(&bot, message(-1001000000025, 25, 2, "/reload"))
+        .await;
+
+    let replies = api.requests("sendMessage");
+    assert_eq!(
+        replies[0]["text"],
+        "Only the operator of this bot can perform this action."
+    );
+    assert_eq!(
+        replies[1]["text"],
+        "Reloaded link preview settings: 9 domains and 4 rewrite rules."
+    );
+}